@@ -2,10 +2,14 @@ extern crate input_linux as input;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+#[macro_use]
+extern crate serde_json;
 
 use std::collections::HashMap;
 use input::{Key, InputEvent, EventRef};
 
+pub mod schema;
+
 pub type Config = Vec<ConfigScreen>;
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -23,13 +27,71 @@ pub struct ConfigScreen {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub hotkeys: Vec<ConfigHotkey>,
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    pub key_remap: HashMap<Key, Key>,
+    pub key_remap: HashMap<Key, ConfigRemap>,
+    #[serde(default)]
+    pub translate_layout: bool,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub x_buttons: HashMap<u8, ConfigXButton>,
+    #[serde(default)]
+    pub raw_motion: bool,
+    #[serde(default)]
+    pub sync_locks: bool,
+    #[serde(default)]
+    pub window: ConfigWindow,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clipboard: Option<ConfigClipboard>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub layers: HashMap<String, ConfigLayer>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub grab_layers: HashMap<ConfigGrabMode, Vec<String>>,
 
     #[serde(default)]
     pub qemu: ConfigQemu,
 
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub exit_events: Vec<ConfigEvent>,
+
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub profiles: HashMap<String, ConfigProfile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+impl ConfigScreen {
+    pub fn with_profile(&self, name: &str) -> Option<ConfigScreen> {
+        self.profiles.get(name).map(|profile| {
+            let mut screen = self.clone();
+            if let Some(ref guest_source) = profile.guest_source {
+                screen.guest_source = guest_source.clone();
+            }
+            if let Some(ref ddc) = profile.ddc {
+                screen.ddc = ddc.clone();
+            }
+            if let Some(ref qemu) = profile.qemu {
+                screen.qemu = qemu.clone();
+            }
+            screen.hotkeys.extend(profile.hotkeys.iter().cloned());
+            screen.key_remap.extend(profile.key_remap.iter().map(|(&k, v)| (k, v.clone())));
+            screen
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ConfigProfile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_source: Option<ConfigInput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ddc: Option<ConfigDdc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qemu: Option<ConfigQemu>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hotkeys: Vec<ConfigHotkey>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub key_remap: HashMap<Key, ConfigRemap>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grabs: Vec<ConfigGrab>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -60,6 +122,28 @@ pub enum ConfigDdcGuest {
     None,
     GuestExec(Vec<String>),
     Exec(Vec<String>),
+    Ssh {
+        host: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        command: Vec<String>,
+        #[serde(default = "ConfigDdcGuest::default_connect_timeout")]
+        connect_timeout: u64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        options: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        program: Option<String>,
+    },
+}
+
+impl ConfigDdcGuest {
+    fn default_connect_timeout() -> u64 {
+        5
+    }
 }
 
 impl Default for ConfigDdcGuest {
@@ -121,6 +205,10 @@ pub struct ConfigHotkey {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub triggers: Vec<Key>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keysyms: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wheel: Vec<ConfigWheel>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub modifiers: Vec<Key>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub events: Vec<ConfigEvent>,
@@ -128,12 +216,110 @@ pub struct ConfigHotkey {
     pub on_release: bool,
     #[serde(default)]
     pub global: bool,
+    #[serde(default)]
+    pub consume: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sequence: Vec<ConfigHotkeyStep>,
+    #[serde(default = "ConfigHotkey::default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub mode: ConfigHotkeyMode,
+    #[serde(default = "ConfigHotkey::default_threshold_ms")]
+    pub threshold_ms: u64,
+}
+
+impl ConfigHotkey {
+    fn default_timeout_ms() -> u64 {
+        500
+    }
+
+    fn default_threshold_ms() -> u64 {
+        250
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigWheel {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigHotkeyMode {
+    Normal,
+    Tap,
+    Hold,
+    DoubleTap,
+}
+
+impl Default for ConfigHotkeyMode {
+    fn default() -> Self {
+        ConfigHotkeyMode::Normal
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ConfigLayer {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub key_remap: HashMap<Key, ConfigRemap>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hold: Vec<Key>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ConfigRemap {
+    Chord(ConfigChord),
+    Sequence {
+        sequence: Vec<ConfigChord>,
+    },
+    Text {
+        text: String,
+    },
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ConfigXButton {
+    Button(Key),
+    Wheel(ConfigWheel),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ConfigChord {
+    Key(Key),
+    Keys(Vec<Key>),
+}
+
+impl ConfigChord {
+    pub fn keys(&self) -> Vec<Key> {
+        match *self {
+            ConfigChord::Key(key) => vec![key],
+            ConfigChord::Keys(ref keys) => keys.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ConfigHotkeyStep {
+    Key(Key),
+    Chord {
+        triggers: Vec<Key>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        modifiers: Vec<Key>,
+    },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ConfigEvent {
-    Exec(Vec<String>),
+    Exec(ConfigExec),
     ShowHost,
     ShowGuest,
     ToggleShow,
@@ -142,11 +328,51 @@ pub enum ConfigEvent {
     Ungrab(ConfigGrabMode),
     UnstickHost,
     UnstickGuest,
+    SwitchProfile(String),
+    EnableLayer(String),
+    DisableLayer(String),
+    ToggleLayer(String),
     Shutdown,
     Reboot,
     Exit,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ConfigExec {
+    Args(Vec<String>),
+    Command(ConfigExecCommand),
+}
+
+impl ConfigExec {
+    pub fn command(&self) -> ConfigExecCommand {
+        match *self {
+            ConfigExec::Args(ref args) => ConfigExecCommand {
+                args: args.clone(),
+                .. Default::default()
+            },
+            ConfigExec::Command(ref command) => command.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ConfigExecCommand {
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub detach: bool,
+    #[serde(default)]
+    pub capture_output: bool,
+    #[serde(default)]
+    pub template: bool,
+}
+
 #[derive(Debug, Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ConfigInputEvent {
@@ -193,7 +419,7 @@ impl ConfigInputEvent {
         !Self::button_match(key)
     }
 
-    fn button_match(key: Key) -> bool {
+    pub fn button_match(key: Key) -> bool {
         let key = key as u16;
         (key >= Key::Button0 as _) && (key < Key::KeyOk as _)
     }
@@ -203,6 +429,7 @@ impl ConfigInputEvent {
 #[serde(rename_all = "lowercase")]
 pub enum ConfigGrab {
     XCore,
+    XLock,
     XDevice {
         devices: Vec<String>,
     },
@@ -223,6 +450,7 @@ impl ConfigGrab {
     pub fn mode(&self) -> ConfigGrabMode {
         match *self {
             ConfigGrab::XCore => ConfigGrabMode::XCore,
+            ConfigGrab::XLock => ConfigGrabMode::XLock,
             ConfigGrab::XDevice { .. } => ConfigGrabMode::XDevice,
             ConfigGrab::Evdev { .. } => ConfigGrabMode::Evdev,
         }
@@ -241,6 +469,18 @@ pub enum ConfigGrabMode {
     Evdev,
     XDevice,
     XCore,
+    XLock,
+}
+
+impl ConfigGrabMode {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ConfigGrabMode::Evdev => "evdev",
+            ConfigGrabMode::XDevice => "xdevice",
+            ConfigGrabMode::XCore => "xcore",
+            ConfigGrabMode::XLock => "xlock",
+        }
+    }
 }
 
 impl Default for ConfigGrabMode {
@@ -249,6 +489,45 @@ impl Default for ConfigGrabMode {
     }
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConfigClipboard {
+    // guest command that prints the clipboard text
+    pub read: Vec<String>,
+    // guest command that sets the clipboard text from its input
+    pub write: Vec<String>,
+    #[serde(default = "ConfigClipboard::default_selections")]
+    pub selections: Vec<ConfigSelection>,
+}
+
+impl ConfigClipboard {
+    fn default_selections() -> Vec<ConfigSelection> {
+        vec![ConfigSelection::Clipboard]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSelection {
+    Clipboard,
+    Primary,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ConfigWindow {
+    #[serde(default)]
+    pub windowed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<(u16, u16)>,
+}
+
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct ConfigMonitor {
     #[serde(default, skip_serializing_if = "Option::is_none")]
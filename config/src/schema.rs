@@ -0,0 +1,623 @@
+use serde::Serialize;
+use serde_json::{self, Value, Map};
+use input::Key;
+use {
+    ConfigDdcHost, ConfigQemuComm, ConfigQemuDriver, ConfigInputEvent, ConfigGrabMode,
+    ConfigHotkeyMode, ConfigWheel, ConfigSelection,
+};
+
+pub fn config_schema() -> Value {
+    let mut definitions = Map::new();
+    definitions.insert("ConfigScreen".into(), screen());
+    definitions.insert("ConfigProfile".into(), profile());
+    definitions.insert("ConfigWindow".into(), window());
+    definitions.insert("ConfigClipboard".into(), clipboard());
+    definitions.insert("ConfigMonitor".into(), monitor());
+    definitions.insert("ConfigInput".into(), input());
+    definitions.insert("ConfigDdc".into(), ddc());
+    definitions.insert("ConfigDdcHost".into(), ddc_host());
+    definitions.insert("ConfigDdcGuest".into(), ddc_guest());
+    definitions.insert("ConfigQemu".into(), qemu());
+    definitions.insert("ConfigHotkey".into(), hotkey());
+    definitions.insert("ConfigHotkeyStep".into(), hotkey_step());
+    definitions.insert("ConfigRemap".into(), remap());
+    definitions.insert("ConfigLayer".into(), object(json!({
+        "key_remap": key_map(reference("ConfigRemap")),
+        "hold": array(reference("Key")),
+    })));
+    definitions.insert("ConfigWheel".into(), unit_variants(&[
+        ConfigWheel::Up, ConfigWheel::Down, ConfigWheel::Left, ConfigWheel::Right,
+    ]));
+    definitions.insert("ConfigXButton".into(), json!({
+        "oneOf": [reference("Key"), reference("ConfigWheel")],
+    }));
+    definitions.insert("ConfigChord".into(), json!({
+        "oneOf": [reference("Key"), array(reference("Key"))],
+    }));
+    definitions.insert("ConfigEvent".into(), event());
+    definitions.insert("ConfigExec".into(), exec());
+    definitions.insert("ConfigGrab".into(), grab());
+    definitions.insert("ConfigGrabMode".into(), unit_variants(&[
+        ConfigGrabMode::Evdev, ConfigGrabMode::XDevice, ConfigGrabMode::XCore, ConfigGrabMode::XLock,
+    ]));
+    definitions.insert("ConfigInputEvent".into(), unit_variants(&[
+        ConfigInputEvent::Key, ConfigInputEvent::Button, ConfigInputEvent::Relative, ConfigInputEvent::Absolute,
+        ConfigInputEvent::Misc, ConfigInputEvent::Switch, ConfigInputEvent::Led, ConfigInputEvent::Sound,
+    ]));
+    definitions.insert("Key".into(), unit_variants(&Key::iter().collect::<Vec<_>>()));
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "screenstub configuration",
+        "type": "array",
+        "items": reference("ConfigScreen"),
+        "definitions": definitions,
+    })
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/definitions/{}", name) })
+}
+
+fn array(items: Value) -> Value {
+    json!({
+        "type": "array",
+        "items": items,
+    })
+}
+
+fn strings() -> Value {
+    array(json!({ "type": "string" }))
+}
+
+fn object(properties: Value) -> Value {
+    json!({
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+fn key_map(value: Value) -> Value {
+    json!({
+        "type": "object",
+        "propertyNames": reference("Key"),
+        "additionalProperties": value,
+    })
+}
+
+fn unit_variants<T: Serialize>(variants: &[T]) -> Value {
+    let names: Vec<_> = variants.iter()
+        .filter_map(|v| match serde_json::to_value(v) {
+            Ok(Value::String(name)) => Some(name),
+            _ => None,
+        }).collect();
+
+    json!({
+        "type": "string",
+        "enum": names,
+    })
+}
+
+fn variant(name: &str, value: Value) -> Value {
+    let mut properties = Map::new();
+    properties.insert(name.into(), value);
+
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": [name],
+        "additionalProperties": false,
+    })
+}
+
+fn screen() -> Value {
+    object(json!({
+        "monitor": reference("ConfigMonitor"),
+        "guest_source": reference("ConfigInput"),
+        "host_source": reference("ConfigInput"),
+        "ddc": reference("ConfigDdc"),
+        "hotkeys": array(reference("ConfigHotkey")),
+        "key_remap": key_map(reference("ConfigRemap")),
+        "translate_layout": { "type": "boolean" },
+        "x_buttons": {
+            "type": "object",
+            "propertyNames": { "pattern": "^[0-9]+$" },
+            "additionalProperties": reference("ConfigXButton"),
+        },
+        "raw_motion": { "type": "boolean" },
+        "sync_locks": { "type": "boolean" },
+        "window": reference("ConfigWindow"),
+        "clipboard": reference("ConfigClipboard"),
+        "layers": {
+            "type": "object",
+            "additionalProperties": reference("ConfigLayer"),
+        },
+        "grab_layers": object(json!({
+            "evdev": strings(),
+            "xdevice": strings(),
+            "xcore": strings(),
+            "xlock": strings(),
+        })),
+        "qemu": reference("ConfigQemu"),
+        "exit_events": array(reference("ConfigEvent")),
+        "profiles": {
+            "type": "object",
+            "additionalProperties": reference("ConfigProfile"),
+        },
+        "profile": { "type": "string" },
+    }))
+}
+
+fn profile() -> Value {
+    object(json!({
+        "guest_source": reference("ConfigInput"),
+        "ddc": reference("ConfigDdc"),
+        "qemu": reference("ConfigQemu"),
+        "hotkeys": array(reference("ConfigHotkey")),
+        "key_remap": key_map(reference("ConfigRemap")),
+        "grabs": array(reference("ConfigGrab")),
+    }))
+}
+
+fn window() -> Value {
+    let size = json!({ "type": "integer", "minimum": 1, "maximum": 65535 });
+    object(json!({
+        "windowed": { "type": "boolean" },
+        "width": size,
+        "height": size,
+        "title": { "type": "string" },
+        "class": { "type": "string" },
+        "aspect_ratio": {
+            "type": "array",
+            "items": size,
+            "minItems": 2,
+            "maxItems": 2,
+        },
+    }))
+}
+
+fn clipboard() -> Value {
+    let mut clipboard = object(json!({
+        "read": strings(),
+        "write": strings(),
+        "selections": array(unit_variants(&[
+            ConfigSelection::Clipboard, ConfigSelection::Primary,
+        ])),
+    }));
+    clipboard["required"] = json!(["read", "write"]);
+
+    clipboard
+}
+
+fn monitor() -> Value {
+    object(json!({
+        "manufacturer": { "type": "string" },
+        "model": { "type": "string" },
+        "serial": { "type": "string" },
+    }))
+}
+
+fn input() -> Value {
+    object(json!({
+        "value": { "type": "integer", "minimum": 0, "maximum": 255 },
+        "name": { "type": "string" },
+    }))
+}
+
+fn ddc() -> Value {
+    object(json!({
+        "host": reference("ConfigDdcHost"),
+        "guest": reference("ConfigDdcGuest"),
+    }))
+}
+
+fn ddc_host() -> Value {
+    let units = vec![
+        ConfigDdcHost::None,
+        #[cfg(feature = "with-ddcutil")]
+        ConfigDdcHost::Libddcutil,
+        ConfigDdcHost::Ddcutil,
+    ];
+
+    json!({
+        "oneOf": [
+            unit_variants(&units),
+            variant("exec", strings()),
+        ],
+    })
+}
+
+fn ddc_guest() -> Value {
+    let mut ssh = object(json!({
+        "host": { "type": "string" },
+        "user": { "type": "string" },
+        "port": { "type": "integer", "minimum": 0, "maximum": 65535 },
+        "identity": { "type": "string" },
+        "command": strings(),
+        "connect_timeout": { "type": "integer", "minimum": 0 },
+        "options": strings(),
+        "program": { "type": "string" },
+    }));
+    ssh["required"] = json!(["host", "command"]);
+
+    json!({
+        "oneOf": [
+            { "type": "string", "enum": ["none"] },
+            variant("guest_exec", strings()),
+            variant("exec", strings()),
+            variant("ssh", ssh),
+        ],
+    })
+}
+
+fn qemu() -> Value {
+    object(json!({
+        "ga_socket": { "type": "string" },
+        "qmp_socket": { "type": "string" },
+        "comm": unit_variants(&[
+            ConfigQemuComm::None, ConfigQemuComm::Qemucomm, ConfigQemuComm::QMP, ConfigQemuComm::Console,
+        ]),
+        "driver": unit_variants(&[
+            ConfigQemuDriver::InputLinux, ConfigQemuDriver::Virtio,
+        ]),
+    }))
+}
+
+fn hotkey() -> Value {
+    object(json!({
+        "triggers": array(reference("Key")),
+        "keysyms": strings(),
+        "wheel": array(reference("ConfigWheel")),
+        "modifiers": array(reference("Key")),
+        "events": array(reference("ConfigEvent")),
+        "on_release": { "type": "boolean" },
+        "global": { "type": "boolean" },
+        "consume": { "type": "boolean" },
+        "sequence": array(reference("ConfigHotkeyStep")),
+        "timeout_ms": { "type": "integer", "minimum": 0 },
+        "mode": unit_variants(&[
+            ConfigHotkeyMode::Normal, ConfigHotkeyMode::Tap, ConfigHotkeyMode::Hold, ConfigHotkeyMode::DoubleTap,
+        ]),
+        "threshold_ms": { "type": "integer", "minimum": 0 },
+    }))
+}
+
+fn remap() -> Value {
+    let mut sequence = object(json!({
+        "sequence": array(reference("ConfigChord")),
+    }));
+    sequence["required"] = json!(["sequence"]);
+    let mut text = object(json!({
+        "text": { "type": "string" },
+    }));
+    text["required"] = json!(["text"]);
+
+    json!({
+        "oneOf": [reference("ConfigChord"), sequence, text],
+    })
+}
+
+fn hotkey_step() -> Value {
+    let mut chord = object(json!({
+        "triggers": array(reference("Key")),
+        "modifiers": array(reference("Key")),
+    }));
+    chord["required"] = json!(["triggers"]);
+
+    json!({
+        "oneOf": [reference("Key"), chord],
+    })
+}
+
+fn event() -> Value {
+    json!({
+        "oneOf": [
+            {
+                "type": "string",
+                "enum": [
+                    "show_host", "show_guest", "toggle_show",
+                    "unstick_host", "unstick_guest",
+                    "shutdown", "reboot", "exit",
+                ],
+            },
+            variant("exec", reference("ConfigExec")),
+            variant("toggle_grab", reference("ConfigGrab")),
+            variant("grab", reference("ConfigGrab")),
+            variant("ungrab", reference("ConfigGrabMode")),
+            variant("switch_profile", json!({ "type": "string" })),
+            variant("enable_layer", json!({ "type": "string" })),
+            variant("disable_layer", json!({ "type": "string" })),
+            variant("toggle_layer", json!({ "type": "string" })),
+        ],
+    })
+}
+
+fn exec() -> Value {
+    let mut command = object(json!({
+        "args": strings(),
+        "env": {
+            "type": "object",
+            "additionalProperties": { "type": "string" },
+        },
+        "cwd": { "type": "string" },
+        "timeout_ms": { "type": "integer", "minimum": 0 },
+        "detach": { "type": "boolean" },
+        "capture_output": { "type": "boolean" },
+        "template": { "type": "boolean" },
+    }));
+    command["required"] = json!(["args"]);
+
+    json!({
+        "oneOf": [
+            strings(),
+            command,
+        ],
+    })
+}
+
+fn grab() -> Value {
+    let mut evdev = object(json!({
+        "exclusive": { "type": "boolean" },
+        "new_device_name": { "type": "string" },
+        "xcore_ignore": array(reference("ConfigInputEvent")),
+        "evdev_ignore": array(reference("ConfigInputEvent")),
+        "devices": strings(),
+    }));
+    evdev["required"] = json!(["devices"]);
+
+    let mut xdevice = object(json!({
+        "devices": strings(),
+    }));
+    xdevice["required"] = json!(["devices"]);
+
+    json!({
+        "oneOf": [
+            { "type": "string", "enum": ["xcore", "xlock"] },
+            variant("xdevice", xdevice),
+            variant("evdev", evdev),
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_yaml;
+
+    use serde_json::Value;
+    use Config;
+    use super::config_schema;
+
+    const KEYWORDS: &'static [&'static str] = &[
+        "$schema", "$ref", "title", "definitions", "type", "enum", "oneOf", "minimum", "maximum",
+        "items", "minItems", "maxItems", "properties", "required", "additionalProperties",
+        "propertyNames", "pattern",
+    ];
+
+    // a validator for the subset of JSON Schema that config_schema uses
+    fn validate(root: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+        let schema = schema.as_object().expect("schema must be an object");
+        if let Some(keyword) = schema.keys().find(|k| !KEYWORDS.contains(&&k[..])) {
+            panic!("unsupported schema keyword {}", keyword);
+        }
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = &reference["#/definitions/".len()..];
+            return validate(root, &root["definitions"][name], value)
+        }
+
+        if let Some(kind) = schema.get("type").and_then(Value::as_str) {
+            let matches = match kind {
+                "object" => value.is_object(),
+                "array" => value.is_array(),
+                "string" => value.is_string(),
+                "integer" => value.is_u64() || value.is_i64(),
+                "boolean" => value.is_boolean(),
+                _ => panic!("unsupported type {}", kind),
+            };
+            if !matches {
+                return Err(format!("{} is not of type {}", value, kind))
+            }
+        }
+
+        if let Some(variants) = schema.get("enum").and_then(Value::as_array) {
+            if !variants.contains(value) {
+                return Err(format!("{} is not one of {:?}", value, variants))
+            }
+        }
+
+        if let Some(number) = value.as_f64() {
+            if schema.get("minimum").and_then(Value::as_f64).map(|min| number < min).unwrap_or(false) ||
+                schema.get("maximum").and_then(Value::as_f64).map(|max| number > max).unwrap_or(false) {
+                return Err(format!("{} is out of range", value))
+            }
+        }
+
+        if let Some(string) = value.as_str() {
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                assert_eq!(pattern, "^[0-9]+$", "unsupported pattern");
+                if string.is_empty() || !string.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(format!("{:?} doesn't match {}", string, pattern))
+                }
+            }
+        }
+
+        if let Some(items) = value.as_array() {
+            let len = items.len() as u64;
+            if schema.get("minItems").and_then(Value::as_u64).map(|min| len < min).unwrap_or(false) ||
+                schema.get("maxItems").and_then(Value::as_u64).map(|max| len > max).unwrap_or(false) {
+                return Err(format!("{} has the wrong number of items", value))
+            }
+            if let Some(item) = schema.get("items") {
+                for value in items {
+                    validate(root, item, value)?;
+                }
+            }
+        }
+
+        if let Some(object) = value.as_object() {
+            if let Some(required) = schema.get("required").and_then(Value::as_array) {
+                if let Some(name) = required.iter().filter_map(Value::as_str).find(|&name| !object.contains_key(name)) {
+                    return Err(format!("{} is missing {}", value, name))
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, value) in object {
+                if let Some(names) = schema.get("propertyNames") {
+                    validate(root, names, &Value::String(name.clone()))?;
+                }
+                match (properties.and_then(|p| p.get(name)), schema.get("additionalProperties")) {
+                    (Some(property), _) => validate(root, property, value)?,
+                    (None, Some(&Value::Bool(false))) => return Err(format!("unknown property {}", name)),
+                    (None, Some(additional)) => validate(root, additional, value)?,
+                    (None, None) => (),
+                }
+            }
+        }
+
+        if let Some(schemas) = schema.get("oneOf").and_then(Value::as_array) {
+            let matched = schemas.iter().filter(|schema| validate(root, schema, value).is_ok()).count();
+            if matched != 1 {
+                return Err(format!("{} matches {} of {:?}", value, matched, schemas))
+            }
+        }
+
+        Ok(())
+    }
+
+    // the config must both deserialize and match the schema
+    fn check(config: &str) -> Result<(), String> {
+        let value: Value = serde_yaml::from_str(config).map_err(|e| e.to_string())?;
+        let schema = config_schema();
+        validate(&schema, &schema, &value)?;
+        serde_yaml::from_str::<Config>(config).map(drop).map_err(|e| e.to_string())
+    }
+
+    // the sample uses the default libddcutil host
+    #[cfg(feature = "with-ddcutil")]
+    #[test]
+    fn sample() {
+        check(include_str!("../../samples/config.yml")).unwrap();
+    }
+
+    #[test]
+    fn shapes() {
+        check(r#"
+- key_remap:
+    KeyLeftMeta: KeyReserved
+    KeyCapsLock: [KeyLeftCtrl, KeySpace]
+    KeyF11:
+      sequence: [[KeyLeftCtrl, KeyA], KeyB]
+    KeyF12:
+      text: "hello world\n"
+  layers:
+    nav:
+      hold: [KeyCapsLock]
+      key_remap:
+        KeyH: KeyLeft
+  grab_layers:
+    evdev: [nav]
+    xlock: [nav]
+  x_buttons:
+    1: ButtonLeft
+    4: up
+  translate_layout: true
+  raw_motion: true
+  sync_locks: true
+  window:
+    windowed: true
+    width: 1280
+    height: 720
+    title: screenstub
+    class: screenstub
+    aspect_ratio: [1920, 1080]
+  clipboard:
+    read: [powershell, Get-Clipboard]
+    write: [powershell, Set-Clipboard]
+    selections: [clipboard, primary]
+  ddc:
+    host:
+      exec: [ddccontrol, "{}"]
+    guest:
+      ssh:
+        host: vm
+        user: user
+        port: 22
+        identity: ~/.ssh/id_vm
+        connect_timeout: 5
+        options: [StrictHostKeyChecking=accept-new]
+        program: ssh
+        command: [ddcutil, setvcp, "0x60", "{value}"]
+  hotkeys:
+  - keysyms: [XK_grave, "0x00e9", U+00E9]
+    events: [toggle_show]
+  - triggers: [KeyRightCtrl]
+    mode: double_tap
+    threshold_ms: 300
+    events:
+    - toggle_grab: xlock
+    - toggle_grab:
+        xdevice:
+          devices: [keyboard]
+  - wheel: [up, left]
+    modifiers: [KeyLeftMeta]
+    consume: true
+    global: false
+    events:
+    - switch_profile: work
+    - toggle_layer: nav
+    - enable_layer: nav
+    - disable_layer: nav
+    - ungrab: xdevice
+  - triggers: [KeyScrollLock]
+    sequence:
+    - KeyS
+    - triggers: [KeyG]
+      modifiers: [KeyLeftShift]
+    timeout_ms: 300
+    events:
+    - exec:
+        args: [notify-send, "{profile}"]
+        env:
+          DISPLAY: ":0"
+        cwd: /tmp
+        timeout_ms: 5000
+        detach: false
+        capture_output: true
+        template: true
+  profile: work
+  profiles:
+    work:
+      guest_source:
+        name: HDMI-2
+      ddc:
+        guest:
+          guest_exec: [ddcset, "{}"]
+      qemu:
+        qmp_socket: /tmp/qmp
+        ga_socket: /tmp/qga
+        comm: none
+        driver: virtio
+      key_remap:
+        KeyCapsLock: KeyLeftCtrl
+      grabs:
+      - xcore
+      - evdev:
+          devices: [/dev/input/mouse]
+      hotkeys:
+      - triggers: [KeyW]
+        events:
+        - switch_profile: gaming
+"#).unwrap();
+    }
+
+    #[test]
+    fn rejected() {
+        assert!(check("- unknown: true").is_err());
+        assert!(check("- window: { aspect_ratio: [16, 9, 1] }").is_err());
+        assert!(check("- x_buttons: { side: ButtonSide }").is_err());
+        assert!(check("- key_remap: { KeyA: { text: a, sequence: [KeyB] } }").is_err());
+        assert!(check("- hotkeys: [{ sequence: [{ modifiers: [KeyA] }] }]").is_err());
+        assert!(check("- clipboard: { read: [cat] }").is_err());
+    }
+}
@@ -113,6 +113,13 @@ impl Monitor {
         }
     }
 
+    pub fn i2c_bus(&self) -> Option<u8> {
+        match self.info().map(|info| info.path()) {
+            Some(DisplayPath::I2c { bus_nbr }) => Some(bus_nbr as u8),
+            _ => None,
+        }
+    }
+
     pub fn our_input(&self) -> Option<u8> {
         match *self {
             Monitor::Search(..) => None,
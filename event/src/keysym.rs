@@ -0,0 +1,177 @@
+use input::Key;
+use x::xcb::Keysym;
+
+// keysyms and the keys that produce them unshifted on a US layout
+static KEYSYMS: &[(&str, Keysym, Key)] = &[
+    ("a", 0x0061, Key::KeyA),
+    ("b", 0x0062, Key::KeyB),
+    ("c", 0x0063, Key::KeyC),
+    ("d", 0x0064, Key::KeyD),
+    ("e", 0x0065, Key::KeyE),
+    ("f", 0x0066, Key::KeyF),
+    ("g", 0x0067, Key::KeyG),
+    ("h", 0x0068, Key::KeyH),
+    ("i", 0x0069, Key::KeyI),
+    ("j", 0x006a, Key::KeyJ),
+    ("k", 0x006b, Key::KeyK),
+    ("l", 0x006c, Key::KeyL),
+    ("m", 0x006d, Key::KeyM),
+    ("n", 0x006e, Key::KeyN),
+    ("o", 0x006f, Key::KeyO),
+    ("p", 0x0070, Key::KeyP),
+    ("q", 0x0071, Key::KeyQ),
+    ("r", 0x0072, Key::KeyR),
+    ("s", 0x0073, Key::KeyS),
+    ("t", 0x0074, Key::KeyT),
+    ("u", 0x0075, Key::KeyU),
+    ("v", 0x0076, Key::KeyV),
+    ("w", 0x0077, Key::KeyW),
+    ("x", 0x0078, Key::KeyX),
+    ("y", 0x0079, Key::KeyY),
+    ("z", 0x007a, Key::KeyZ),
+    ("0", 0x0030, Key::Key0),
+    ("1", 0x0031, Key::Key1),
+    ("2", 0x0032, Key::Key2),
+    ("3", 0x0033, Key::Key3),
+    ("4", 0x0034, Key::Key4),
+    ("5", 0x0035, Key::Key5),
+    ("6", 0x0036, Key::Key6),
+    ("7", 0x0037, Key::Key7),
+    ("8", 0x0038, Key::Key8),
+    ("9", 0x0039, Key::Key9),
+    ("space", 0x0020, Key::KeySpace),
+    ("apostrophe", 0x0027, Key::KeyApostrophe),
+    ("comma", 0x002c, Key::KeyComma),
+    ("minus", 0x002d, Key::KeyMinus),
+    ("period", 0x002e, Key::KeyDot),
+    ("slash", 0x002f, Key::KeySlash),
+    ("semicolon", 0x003b, Key::KeySemicolon),
+    ("equal", 0x003d, Key::KeyEqual),
+    ("bracketleft", 0x005b, Key::KeyLeftBrace),
+    ("backslash", 0x005c, Key::KeyBackslash),
+    ("bracketright", 0x005d, Key::KeyRightBrace),
+    ("grave", 0x0060, Key::KeyGrave),
+    ("BackSpace", 0xff08, Key::KeyBackspace),
+    ("Tab", 0xff09, Key::KeyTab),
+    ("Return", 0xff0d, Key::KeyEnter),
+    ("Pause", 0xff13, Key::KeyPause),
+    ("Scroll_Lock", 0xff14, Key::KeyScrollLock),
+    ("Escape", 0xff1b, Key::KeyEsc),
+    ("Home", 0xff50, Key::KeyHome),
+    ("Left", 0xff51, Key::KeyLeft),
+    ("Up", 0xff52, Key::KeyUp),
+    ("Right", 0xff53, Key::KeyRight),
+    ("Down", 0xff54, Key::KeyDown),
+    ("Page_Up", 0xff55, Key::KeyPageUp),
+    ("Page_Down", 0xff56, Key::KeyPageDown),
+    ("End", 0xff57, Key::KeyEnd),
+    ("Print", 0xff61, Key::KeySysrq),
+    ("Insert", 0xff63, Key::KeyInsert),
+    ("Menu", 0xff67, Key::KeyCompose),
+    ("Num_Lock", 0xff7f, Key::KeyNumLock),
+    ("F1", 0xffbe, Key::KeyF1),
+    ("F2", 0xffbf, Key::KeyF2),
+    ("F3", 0xffc0, Key::KeyF3),
+    ("F4", 0xffc1, Key::KeyF4),
+    ("F5", 0xffc2, Key::KeyF5),
+    ("F6", 0xffc3, Key::KeyF6),
+    ("F7", 0xffc4, Key::KeyF7),
+    ("F8", 0xffc5, Key::KeyF8),
+    ("F9", 0xffc6, Key::KeyF9),
+    ("F10", 0xffc7, Key::KeyF10),
+    ("F11", 0xffc8, Key::KeyF11),
+    ("F12", 0xffc9, Key::KeyF12),
+    ("Shift_L", 0xffe1, Key::KeyLeftShift),
+    ("Shift_R", 0xffe2, Key::KeyRightShift),
+    ("Control_L", 0xffe3, Key::KeyLeftCtrl),
+    ("Control_R", 0xffe4, Key::KeyRightCtrl),
+    ("Caps_Lock", 0xffe5, Key::KeyCapsLock),
+    ("Alt_L", 0xffe9, Key::KeyLeftAlt),
+    ("Alt_R", 0xffea, Key::KeyRightAlt),
+    ("Super_L", 0xffeb, Key::KeyLeftMeta),
+    ("Super_R", 0xffec, Key::KeyRightMeta),
+    ("Delete", 0xffff, Key::KeyDelete),
+];
+
+// accepts names with or without the XK_ prefix, like "XK_grave", as well as
+// raw keysym numbers like "0x00e9" and unicode characters like "U+00E9" for
+// anything not in the table
+pub fn from_name(name: &str) -> Option<Keysym> {
+    let name = if name.starts_with("XK_") { &name[3..] } else { name };
+    if name.starts_with("0x") {
+        Keysym::from_str_radix(&name[2..], 16).ok()
+    } else if name.starts_with("U+") {
+        u32::from_str_radix(&name[2..], 16).ok()
+            .and_then(::std::char::from_u32)
+            .map(unicode_keysym)
+    } else {
+        KEYSYMS.iter()
+            .find(|&&(n, _, _)| n == name)
+            .map(|&(_, keysym, _)| keysym)
+    }
+}
+
+// Latin-1 characters are their own keysyms, everything else is offset into the unicode range
+pub fn unicode_keysym(c: char) -> Keysym {
+    match c {
+        ' ' ..= '~' | '\u{a0}' ..= '\u{ff}' => c as Keysym,
+        c => 0x01000000 + c as Keysym,
+    }
+}
+
+pub fn us_key(keysym: Keysym) -> Option<Key> {
+    KEYSYMS.iter()
+        .find(|&&(_, k, _)| k == keysym)
+        .map(|&(_, _, key)| key)
+}
+
+// the key and whether shift is needed to type a character on a US layout
+pub fn us_char(c: char) -> Option<(Key, bool)> {
+    let shifted = match c {
+        'A' ..= 'Z' => return us_key(c.to_ascii_lowercase() as Keysym).map(|key| (key, true)),
+        '\n' => return Some((Key::KeyEnter, false)),
+        '\t' => return Some((Key::KeyTab, false)),
+        '!' => '1', '@' => '2', '#' => '3', '$' => '4', '%' => '5',
+        '^' => '6', '&' => '7', '*' => '8', '(' => '9', ')' => '0',
+        '_' => '-', '+' => '=', '{' => '[', '}' => ']', '|' => '\\',
+        ':' => ';', '"' => '\'', '<' => ',', '>' => '.', '?' => '/', '~' => '`',
+        ' ' ..= '~' => return us_key(c as Keysym).map(|key| (key, false)),
+        _ => return None,
+    };
+
+    us_key(shifted as Keysym).map(|key| (key, true))
+}
+
+pub fn us_keysym(key: Key) -> Option<Keysym> {
+    KEYSYMS.iter()
+        .find(|&&(_, _, k)| k == key)
+        .map(|&(_, keysym, _)| keysym)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_name;
+
+    #[test]
+    fn names() {
+        assert_eq!(from_name("grave"), Some(0x0060));
+        assert_eq!(from_name("XK_F12"), Some(0xffc9));
+        assert_eq!(from_name("XK_eacute"), None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(from_name("0x00e9"), Some(0x00e9));
+        assert_eq!(from_name("XK_0xfe03"), Some(0xfe03));
+        assert_eq!(from_name("0xzz"), None);
+    }
+
+    #[test]
+    fn unicode() {
+        assert_eq!(from_name("U+00E9"), Some(0x00e9));
+        assert_eq!(from_name("U+0041"), Some(0x0041));
+        assert_eq!(from_name("U+00DF"), Some(0x00df));
+        assert_eq!(from_name("U+0439"), Some(0x01000439));
+        assert_eq!(from_name("U+D800"), None);
+    }
+}
@@ -5,14 +5,18 @@ extern crate input_linux as input;
 
 use std::collections::HashMap;
 use std::rc::Rc;
-use std::{slice, iter};
+use std::time::Duration;
+use std::{slice, iter, mem};
 use input::{
-    EventRef, EventMut, InputEvent, SynchronizeEvent, SynchronizeKind,
+    EventRef, EventTime, InputEvent, SynchronizeEvent, SynchronizeKind,
     KeyEvent, Key, KeyState,
-    AbsoluteEvent, AbsoluteAxis,
-    Bitmask,
+    AbsoluteEvent, AbsoluteAxis, RelativeEvent, RelativeAxis,
+    LedKind, Bitmask,
 };
-use x::{XState, XEvent, xcb};
+use x::{XState, XEvent, XHotkey, XHotkeyKey, XLockState, xcb};
+
+pub mod keysym;
+pub mod record;
 
 #[derive(Debug)]
 pub enum UserEvent {
@@ -22,50 +26,237 @@ pub enum UserEvent {
     UnstickHost,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Wheel {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum XButton {
+    Button(Key),
+    Wheel(Wheel),
+}
+
 #[derive(Debug)]
 pub struct Hotkey<U> {
     triggers: Vec<Key>,
+    keysyms: Vec<xcb::Keysym>,
+    wheel: Vec<Wheel>,
     modifiers: Vec<Key>,
     events: Vec<Rc<U>>,
+    sequence: Vec<HotkeyStep>,
+    timeout: Duration,
+    mode: HotkeyMode,
+    threshold: Duration,
+    consume: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HotkeyMode {
+    Normal,
+    // released within the threshold without pressing anything else
+    Tap,
+    // held past the threshold without pressing anything else
+    Hold,
+    // pressed again within the threshold of a tap
+    DoubleTap,
 }
 
 impl<U> Hotkey<U> {
     pub fn new<T: IntoIterator<Item=Key>, M: IntoIterator<Item=Key>, E: IntoIterator<Item=U>>(triggers: T, modifiers: M, events: E) -> Self {
         Hotkey {
             triggers: triggers.into_iter().collect(),
+            keysyms: Default::default(),
+            wheel: Default::default(),
             modifiers: modifiers.into_iter().collect(),
             events: events.into_iter().map(Rc::new).collect(),
+            sequence: Default::default(),
+            timeout: Default::default(),
+            mode: HotkeyMode::Normal,
+            threshold: Default::default(),
+            consume: false,
         }
     }
+
+    // keep the trigger from reaching the guest, and release any modifiers it already saw
+    pub fn consume(mut self, consume: bool) -> Self {
+        self.consume = consume;
+        self
+    }
+
+    pub fn mode(mut self, mode: HotkeyMode, threshold: Duration) -> Self {
+        self.mode = mode;
+        self.threshold = threshold;
+        self
+    }
+
+    // triggers matched by what the key produces in the host layout
+    pub fn keysyms<K: IntoIterator<Item=xcb::Keysym>>(mut self, keysyms: K) -> Self {
+        self.keysyms = keysyms.into_iter().collect();
+        self
+    }
+
+    // triggers on each scroll in these directions, regardless of mode
+    pub fn wheel<W: IntoIterator<Item=Wheel>>(mut self, wheel: W) -> Self {
+        self.wheel = wheel.into_iter().collect();
+        self
+    }
+
+    // further steps that must follow within `timeout` of each other
+    pub fn sequence<S: IntoIterator<Item=HotkeyStep>>(mut self, sequence: S, timeout: Duration) -> Self {
+        self.sequence = sequence.into_iter().collect();
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn keys(&self) -> iter::Cloned<iter::Chain<slice::Iter<Key>, slice::Iter<Key>>> {
+        self.triggers.iter().chain(self.modifiers.iter()).cloned()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HotkeyStep {
+    triggers: Vec<Key>,
+    modifiers: Vec<Key>,
+}
+
+impl HotkeyStep {
+    pub fn new<T: IntoIterator<Item=Key>, M: IntoIterator<Item=Key>>(triggers: T, modifiers: M) -> Self {
+        HotkeyStep {
+            triggers: triggers.into_iter().collect(),
+            modifiers: modifiers.into_iter().collect(),
+        }
+    }
+
     pub fn keys(&self) -> iter::Cloned<iter::Chain<slice::Iter<Key>, slice::Iter<Key>>> {
         self.triggers.iter().chain(self.modifiers.iter()).cloned()
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum Remap {
+    // pressed and released along with the original key
+    Chord(Vec<Key>),
+    // typed out in full when the original key is pressed
+    Sequence(Vec<Vec<Key>>),
+}
+
+#[derive(Debug, Default)]
+struct Layer {
+    remap: HashMap<Key, Remap>,
+    hold: Vec<Key>,
+}
+
+// event timestamps are only comparable with others from the same clock
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimeSource {
+    // X server time, for events translated from X
+    X,
+    // kernel time, for events read from evdev devices
+    Evdev,
+}
+
+#[derive(Debug)]
+struct Press<U> {
+    key: Key,
+    time: Duration,
+    source: TimeSource,
+    serial: u64,
+    // hold hotkeys still waiting on their threshold
+    holds: Vec<Rc<Hotkey<U>>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Tap {
+    key: Key,
+    source: TimeSource,
+    held: Duration,
+    released: Duration,
+}
+
+// a hold hotkey armed by a key press, to be passed back to
+// `Events::process_hold` once its delay has passed
+#[derive(Debug)]
+pub struct HoldTimer<U> {
+    hotkey: Rc<Hotkey<U>>,
+    press: u64,
+}
+
+impl<U> HoldTimer<U> {
+    pub fn delay(&self) -> Duration {
+        self.hotkey.threshold
+    }
+}
+
+#[derive(Debug)]
+struct PendingSequence<U> {
+    hotkey: Rc<Hotkey<U>>,
+    step: usize,
+    deadline: Duration,
+}
+
 #[derive(Debug)]
 pub struct Events<U> {
     xstate: XState,
     mouse_x: i16,
     mouse_y: i16,
+    aspect_ratio: Option<(u16, u16)>,
     triggers_press: HashMap<Key, Vec<Rc<Hotkey<U>>>>,
     triggers_release: HashMap<Key, Vec<Rc<Hotkey<U>>>>,
-    remap: HashMap<Key, Key>,
+    keysym_triggers_press: HashMap<xcb::Keysym, Vec<Rc<Hotkey<U>>>>,
+    keysym_triggers_release: HashMap<xcb::Keysym, Vec<Rc<Hotkey<U>>>>,
+    wheel_triggers: HashMap<Wheel, Vec<Rc<Hotkey<U>>>>,
+    x_buttons: HashMap<xcb::Button, XButton>,
+    keymap: Vec<xcb::Keysym>,
+    keysyms_per_keycode: usize,
+    translate_layout: bool,
+    global_hotkeys: Vec<(Rc<Hotkey<U>>, bool)>,
+    sequences: Vec<PendingSequence<U>>,
+    last_press: Option<Press<U>>,
+    last_tap: Option<Tap>,
+    presses: u64,
+    remap: HashMap<Key, Remap>,
+    remapped: HashMap<Key, Vec<Key>>,
+    layers: HashMap<String, Layer>,
+    enabled_layers: Vec<String>,
     keys: Bitmask<Key>,
+    consumed: Bitmask<Key>,
+    sync_locks: bool,
+    host_locks: XLockState,
+    // unknown until the guest reports its keyboard LEDs
+    guest_locks: Option<XLockState>,
 }
 
 #[derive(Debug)]
-pub enum ProcessedXEvent {
+pub enum ProcessedXEvent<U> {
     UserEvent(UserEvent),
     InputEvent(InputEvent),
+    // events for the relative device
+    RelativeEvent(InputEvent),
+    HotkeyEvent(Rc<U>),
+    // host selection text to pass on to the guest
+    Clipboard(String),
+    // lock key toggles that go straight to the guest without triggering hotkeys
+    LockSync(InputEvent),
+}
+
+#[derive(Debug)]
+pub enum ProcessedInputEvent<U> {
+    InputEvent(InputEvent),
+    HotkeyEvent(Rc<U>),
+    HoldTimer(HoldTimer<U>),
 }
 
-impl From<UserEvent> for ProcessedXEvent {
+impl<U> From<UserEvent> for ProcessedXEvent<U> {
     fn from(e: UserEvent) -> Self {
         ProcessedXEvent::UserEvent(e)
     }
 }
 
-impl<I: Into<InputEvent>> From<I> for ProcessedXEvent {
+impl<U, I: Into<InputEvent>> From<I> for ProcessedXEvent<U> {
     fn from(e: I) -> Self {
         ProcessedXEvent::InputEvent(e.into())
     }
@@ -77,40 +268,313 @@ impl<U> Events<U> {
             xstate: Default::default(),
             mouse_x: -1,
             mouse_y: -1,
+            aspect_ratio: None,
             triggers_press: Default::default(),
             triggers_release: Default::default(),
+            keysym_triggers_press: Default::default(),
+            keysym_triggers_release: Default::default(),
+            wheel_triggers: Default::default(),
+            x_buttons: [
+                (xcb::BUTTON_INDEX_1, XButton::Button(Key::ButtonLeft)),
+                (xcb::BUTTON_INDEX_2, XButton::Button(Key::ButtonMiddle)),
+                (xcb::BUTTON_INDEX_3, XButton::Button(Key::ButtonRight)),
+                (xcb::BUTTON_INDEX_4, XButton::Wheel(Wheel::Up)),
+                (xcb::BUTTON_INDEX_5, XButton::Wheel(Wheel::Down)),
+                (6, XButton::Wheel(Wheel::Left)),
+                (7, XButton::Wheel(Wheel::Right)),
+                // qemu input-linux doesn't support fwd/back, but virtio probably does
+                (8, XButton::Button(Key::ButtonSide)),
+                (9, XButton::Button(Key::ButtonExtra)),
+            ].iter().map(|&(button, mapping)| (button as _, mapping)).collect(),
+            keymap: Default::default(),
+            keysyms_per_keycode: 0,
+            translate_layout: false,
+            global_hotkeys: Default::default(),
+            sequences: Default::default(),
+            last_press: None,
+            last_tap: None,
+            presses: 0,
             remap: Default::default(),
+            remapped: Default::default(),
+            layers: Default::default(),
+            enabled_layers: Default::default(),
             keys: Default::default(),
+            consumed: Default::default(),
+            sync_locks: false,
+            host_locks: Default::default(),
+            guest_locks: None,
         }
     }
 
     pub fn add_hotkey(&mut self, hotkey: Hotkey<U>, on_press: bool) {
-        let hotkey = Rc::new(hotkey);
-        for &key in &hotkey.triggers {
+        self.add_hotkey_(Rc::new(hotkey), on_press)
+    }
+
+    fn add_hotkey_(&mut self, hotkey: Rc<Hotkey<U>>, on_press: bool) {
+        let on_press = match hotkey.mode {
+            // sequences always advance on press
+            HotkeyMode::Normal => on_press || !hotkey.sequence.is_empty(),
+            HotkeyMode::Tap => false,
+            // armed on press, see `process_hold`
+            HotkeyMode::Hold | HotkeyMode::DoubleTap => true,
+        };
+        // a wheel hotkey's triggers are only held while scrolling
+        let triggers = if hotkey.wheel.is_empty() { &hotkey.triggers[..] } else { &[] };
+        let keysyms = if hotkey.wheel.is_empty() { &hotkey.keysyms[..] } else { &[] };
+        for &key in triggers {
             if on_press {
                 &mut self.triggers_press
             } else {
                 &mut self.triggers_release
             }.entry(key).or_insert(Default::default()).push(hotkey.clone())
         }
+        for &keysym in keysyms {
+            if on_press {
+                &mut self.keysym_triggers_press
+            } else {
+                &mut self.keysym_triggers_release
+            }.entry(keysym).or_insert(Default::default()).push(hotkey.clone())
+        }
+        for &wheel in &hotkey.wheel {
+            self.wheel_triggers.entry(wheel).or_insert(Default::default()).push(hotkey.clone())
+        }
     }
 
-    pub fn add_remap(&mut self, from: Key, to: Key) {
+    pub fn add_global_hotkey(&mut self, hotkey: Hotkey<U>, on_press: bool) {
+        let hotkey = Rc::new(hotkey);
+        // still handled as usual while the window has the keyboard grabbed
+        self.add_hotkey_(hotkey.clone(), on_press);
+        self.global_hotkeys.push((hotkey, on_press));
+    }
+
+    pub fn clear_hotkeys(&mut self) {
+        self.triggers_press.clear();
+        self.triggers_release.clear();
+        self.keysym_triggers_press.clear();
+        self.keysym_triggers_release.clear();
+        self.wheel_triggers.clear();
+        self.global_hotkeys.clear();
+        self.sequences.clear();
+    }
+
+    pub fn x_hotkeys(&self) -> Vec<XHotkey> {
+        self.global_hotkeys.iter().enumerate().filter_map(|(id, &(ref hotkey, _))| {
+            let modifiers = hotkey.modifiers.iter()
+                .map(|&key| Self::x_modifier(key))
+                .fold(Some(0), |mask, m| mask.and_then(|mask| m.map(|m| mask | m)));
+            let key = match (&hotkey.triggers[..], &hotkey.keysyms[..]) {
+                _ if !hotkey.wheel.is_empty() => None,
+                // X keycodes are only a byte wide, which rules out buttons
+                (triggers, keysyms) if triggers.len() == 1 && keysyms.is_empty() && triggers[0] as u16 <= x::MAX_KEYCODE =>
+                    Some(XHotkeyKey::Keycode(triggers[0] as _)),
+                (triggers, keysyms) if triggers.is_empty() && keysyms.len() == 1 =>
+                    Some(XHotkeyKey::Keysym(keysyms[0])),
+                _ => None,
+            };
+            match (key, modifiers) {
+                (Some(key), Some(modifiers)) => Some(XHotkey {
+                    id: id,
+                    key: key,
+                    modifiers: modifiers,
+                }),
+                _ => {
+                    warn!("global hotkey {:?} + {:?} {:?} must have a single trigger and only use modifier keys",
+                        hotkey.modifiers, hotkey.triggers, hotkey.keysyms
+                    );
+                    None
+                },
+            }
+        }).collect()
+    }
+
+    fn x_modifier(key: Key) -> Option<u16> {
+        match key {
+            Key::KeyLeftShift | Key::KeyRightShift => Some(xcb::MOD_MASK_SHIFT as _),
+            Key::KeyLeftCtrl | Key::KeyRightCtrl => Some(xcb::MOD_MASK_CONTROL as _),
+            Key::KeyLeftAlt | Key::KeyRightAlt => Some(xcb::MOD_MASK_1 as _),
+            Key::KeyLeftMeta | Key::KeyRightMeta => Some(xcb::MOD_MASK_4 as _),
+            _ => None,
+        }
+    }
+
+    pub fn add_remap(&mut self, from: Key, to: Remap) {
         self.remap.insert(from, to);
     }
 
-    pub fn x_button(&self, button: xcb::Button) -> Option<Key> {
-        match button as _ {
-            xcb::BUTTON_INDEX_1 => Some(Key::ButtonLeft),
-            xcb::BUTTON_INDEX_2 => Some(Key::ButtonMiddle),
-            xcb::BUTTON_INDEX_3 => Some(Key::ButtonRight),
-            xcb::BUTTON_INDEX_4 => Some(Key::ButtonGearUp),
-            xcb::BUTTON_INDEX_5 => Some(Key::ButtonWheel), // Key::ButtonGearDown
-            // also map Key::ButtonSide, Key::ButtonExtra? qemu input-linux doesn't support fwd/back, but virtio probably does
+    pub fn clear_remap(&mut self) {
+        self.remap.clear();
+    }
+
+    // the layer's remapping takes priority while it's enabled or any of its hold keys are pressed
+    pub fn add_layer<H: IntoIterator<Item=Key>>(&mut self, name: String, remap: HashMap<Key, Remap>, hold: H) {
+        self.layers.insert(name, Layer {
+            remap: remap,
+            hold: hold.into_iter().collect(),
+        });
+    }
+
+    pub fn enable_layer(&mut self, name: &str) -> bool {
+        if !self.layers.contains_key(name) {
+            warn!("unknown layer {}", name);
+            return false
+        }
+
+        if !self.layer_enabled(name) {
+            self.enabled_layers.push(name.to_owned());
+        }
+        true
+    }
+
+    pub fn disable_layer(&mut self, name: &str) -> bool {
+        let enabled = self.layer_enabled(name);
+        self.enabled_layers.retain(|l| l != name);
+        enabled
+    }
+
+    pub fn toggle_layer(&mut self, name: &str) -> bool {
+        if self.layer_enabled(name) {
+            self.disable_layer(name);
+            false
+        } else {
+            self.enable_layer(name)
+        }
+    }
+
+    pub fn layer_enabled(&self, name: &str) -> bool {
+        self.enabled_layers.iter().any(|l| l == name)
+    }
+
+    fn is_layer_key(&self, key: Key) -> bool {
+        self.layers.values().any(|l| l.hold.contains(&key))
+    }
+
+    fn active_remap(&self, key: Key) -> Option<&Remap> {
+        let keys = &self.keys;
+        let held = self.layers.values().filter(|l| l.hold.iter().any(|&k| keys.get(k)));
+        let enabled = self.enabled_layers.iter().rev().filter_map(|l| self.layers.get(l));
+
+        held.chain(enabled)
+            .filter_map(|l| l.remap.get(&key))
+            .next()
+            .or_else(|| self.remap.get(&key))
+    }
+
+    // send keys to the guest by what they produce in the host layout, assuming a US guest layout
+    pub fn set_translate_layout(&mut self, translate: bool) {
+        self.translate_layout = translate;
+    }
+
+    // confine absolute motion to the largest area of this aspect ratio centered in the window
+    pub fn set_aspect_ratio(&mut self, aspect_ratio: Option<(u16, u16)>) {
+        self.aspect_ratio = aspect_ratio;
+    }
+
+    // mirror the guest's lock LEDs onto the host, and toggle the guest's locks to match the host on focus
+    pub fn set_sync_locks(&mut self, sync_locks: bool) {
+        self.sync_locks = sync_locks;
+    }
+
+    // returns the lock state to apply to the host when the guest's LEDs no longer match it
+    pub fn process_led_event(&mut self, e: &InputEvent) -> Option<XLockState> {
+        let led = match EventRef::new(e) {
+            Ok(EventRef::Led(led)) => led,
+            _ => return None,
+        };
+
+        let mut locks = self.guest_locks.unwrap_or(self.host_locks);
+        match led.led {
+            LedKind::CapsLock => locks.caps_lock = led.value != 0,
+            LedKind::NumLock => locks.num_lock = led.value != 0,
+            _ => return None,
+        }
+
+        let changed = self.guest_locks != Some(locks);
+        self.guest_locks = Some(locks);
+        if self.sync_locks && changed && locks != self.host_locks {
+            Some(locks)
+        } else {
+            None
+        }
+    }
+
+    fn lock_sync_events(&self) -> Vec<ProcessedXEvent<U>> {
+        let guest_locks = match self.guest_locks {
+            Some(locks) if self.sync_locks => locks,
+            _ => return Default::default(),
+        };
+
+        [
+            (guest_locks.caps_lock != self.host_locks.caps_lock, Key::KeyCapsLock),
+            (guest_locks.num_lock != self.host_locks.num_lock, Key::KeyNumLock),
+        ].iter()
+            // a remapped lock key wouldn't toggle anything
+            .filter(|&&(differs, key)| differs && self.active_remap(key).is_none())
+            .flat_map(|&(_, key)| vec![
+                KeyEvent::new(Default::default(), key, Self::key_state(true)).into(),
+                Self::sync_report(),
+                KeyEvent::new(Default::default(), key, Self::key_state(false)).into(),
+                Self::sync_report(),
+            ])
+            .map(ProcessedXEvent::LockSync)
+            .collect()
+    }
+
+    fn viewport(&self) -> (i16, i16, u16, u16) {
+        let (width, height) = (self.xstate.width, self.xstate.height);
+        match self.aspect_ratio {
+            Some((w, h)) if w != 0 && h != 0 => {
+                let (w, h) = if width as u32 * h as u32 > height as u32 * w as u32 {
+                    ((height as u32 * w as u32 / h as u32) as u16, height)
+                } else {
+                    (width, (width as u32 * h as u32 / w as u32) as u16)
+                };
+                (((width - w) / 2) as _, ((height - h) / 2) as _, w, h)
+            },
+            _ => (0, 0, width, height),
+        }
+    }
+
+    fn key_keysyms(&self, key: Key) -> Vec<xcb::Keysym> {
+        if self.translate_layout {
+            // keys have already been translated to the guest layout
+            keysym::us_keysym(key).into_iter().collect()
+        } else {
+            // only the unshifted and shifted levels of the first group
+            let start = key as usize * self.keysyms_per_keycode;
+            let len = if self.keysyms_per_keycode < 2 { self.keysyms_per_keycode } else { 2 };
+            self.keymap.get(start..start + len).unwrap_or(&[]).to_owned()
+        }
+    }
+
+    pub fn x_button(&self, button: xcb::Button) -> Option<XButton> {
+        self.x_buttons.get(&button).cloned()
+    }
+
+    pub fn set_x_button(&mut self, button: xcb::Button, mapping: Option<XButton>) {
+        match mapping {
+            Some(mapping) => self.x_buttons.insert(button, mapping),
+            None => self.x_buttons.remove(&button),
+        };
+    }
+
+    fn rel_wheel(axis: RelativeAxis, value: i32) -> Option<Wheel> {
+        match (axis, value) {
+            (RelativeAxis::Wheel, v) if v > 0 => Some(Wheel::Up),
+            (RelativeAxis::Wheel, v) if v < 0 => Some(Wheel::Down),
+            (RelativeAxis::HorizontalWheel, v) if v > 0 => Some(Wheel::Right),
+            (RelativeAxis::HorizontalWheel, v) if v < 0 => Some(Wheel::Left),
             _ => None,
         }
     }
 
+    fn process_wheel(&self, wheel: Wheel) -> Vec<Rc<Hotkey<U>>> {
+        let keys = &self.keys;
+        self.wheel_triggers.get(&wheel).into_iter().flat_map(|h| h.iter())
+            .filter(|h| h.keys().all(|k| keys.get(k)))
+            .cloned()
+            .collect()
+    }
+
     pub fn x_keycode(&self, key: xcb::Keycode) -> Option<Key> {
         match Key::from_code(key as _) {
             Ok(code) => Some(code),
@@ -118,57 +582,278 @@ impl<U> Events<U> {
         }
     }
 
-    pub fn x_keysym(&self, _key: xcb::Keysym) -> Option<Key> {
-        unimplemented!()
+    pub fn x_keysym(&self, key: xcb::Keysym) -> Option<Key> {
+        keysym::us_key(key)
     }
 
-    pub fn map_input_event(&mut self, mut e: InputEvent) -> InputEvent {
-        let key = match EventMut::new(&mut e) {
-            Ok(EventMut::Key(key)) => if let Some(remap) = self.remap.get(&key.key) {
-                key.key = *remap;
-            },
-            _ => (),
+    fn map_input_event(&mut self, e: InputEvent) -> Vec<InputEvent> {
+        let (time, key, state) = match EventRef::new(&e) {
+            Ok(EventRef::Key(key)) => (key.time, key.key, key.key_state()),
+            _ => return vec![e],
         };
 
-        e
+        match state {
+            KeyState::Pressed => match self.active_remap(key).cloned() {
+                Some(Remap::Chord(keys)) => {
+                    let events = keys.iter()
+                        .map(|&k| KeyEvent::new(time, k, Self::key_state(true)).into())
+                        .collect();
+                    self.remapped.insert(key, keys);
+                    events
+                },
+                Some(Remap::Sequence(chords)) => {
+                    self.remapped.insert(key, Vec::new());
+                    chords.iter().flat_map(|chord| Self::chord_events(time, chord)).collect()
+                },
+                None => vec![e],
+            },
+            // releases what the press was mapped to, even if the remap has since changed
+            KeyState::Released => match self.remapped.remove(&key) {
+                Some(keys) => keys.iter().rev()
+                    .map(|&k| KeyEvent::new(time, k, Self::key_state(false)).into())
+                    .collect(),
+                None => vec![e],
+            },
+            _ => match self.remapped.get(&key) {
+                Some(keys) => keys.last()
+                    .map(|&k| KeyEvent::new(time, k, KeyState::Autorepeat.into()).into())
+                    .into_iter().collect(),
+                None => vec![e],
+            },
+        }
+    }
+
+    fn chord_events(time: EventTime, chord: &[Key]) -> Vec<InputEvent> {
+        chord.iter().map(|&k| KeyEvent::new(time, k, Self::key_state(true)).into())
+            .chain(iter::once(Self::sync_report()))
+            .chain(chord.iter().rev().map(|&k| KeyEvent::new(time, k, Self::key_state(false)).into()))
+            .chain(iter::once(Self::sync_report()))
+            .collect()
     }
 
-    pub fn process_input_event(&mut self, e: &InputEvent) -> Vec<Rc<U>> {
-        match EventRef::new(e) {
-            Ok(e) => self.process_input_event_(e),
+    pub fn process_input_event(&mut self, e: InputEvent, source: TimeSource) -> Vec<ProcessedInputEvent<U>> {
+        let (key, hotkeys) = match EventRef::new(&e) {
+            Ok(EventRef::Key(key)) => (Some((key.key, key.key_state())), self.process_input_event_(EventRef::Key(key), source)),
+            Ok(e) => (None, self.process_input_event_(e, source)),
             Err(err) => {
                 warn!("Unable to parse input event {:?} due to {:?}", e, err);
-                Default::default()
+                (None, Default::default())
+            },
+        };
+
+        let mut res: Vec<_> = hotkeys.iter()
+            .flat_map(|h| h.events.iter().cloned())
+            .map(ProcessedInputEvent::HotkeyEvent)
+            .collect();
+
+        if let Some((_, KeyState::Pressed)) = key {
+            if let Some(ref press) = self.last_press {
+                res.extend(press.holds.iter().map(|h| ProcessedInputEvent::HoldTimer(HoldTimer {
+                    hotkey: h.clone(),
+                    press: press.serial,
+                })));
+            }
+        }
+
+        let forward = match key {
+            // layer keys only switch layers and aren't seen by the guest
+            Some((key, _)) if self.is_layer_key(key) => false,
+            Some((key, state)) if self.consumed.get(key) => {
+                if state == KeyState::Released {
+                    self.consumed.clear(key);
+                }
+                false
+            },
+            Some((key, state)) if hotkeys.iter().any(|h| h.consume) => {
+                let releases = self.release_modifiers(&hotkeys, Some(key));
+                res.extend(releases.into_iter().map(ProcessedInputEvent::InputEvent));
+
+                // the press was never seen by the guest, so neither should the release be
+                if state == KeyState::Pressed {
+                    self.consumed.set(key);
+                    false
+                } else {
+                    true
+                }
+            },
+            None if hotkeys.iter().any(|h| h.consume) => {
+                let releases = self.release_modifiers(&hotkeys, None);
+                res.extend(releases.into_iter().map(ProcessedInputEvent::InputEvent));
+                false
+            },
+            _ => true,
+        };
+
+        if forward {
+            let mapped = self.map_input_event(e);
+            res.extend(mapped.into_iter().map(ProcessedInputEvent::InputEvent));
+        }
+
+        res
+    }
+
+    // fires the hold hotkey if its key is still held and nothing else has
+    // been pressed since
+    pub fn process_hold(&mut self, timer: HoldTimer<U>) -> Vec<ProcessedInputEvent<U>> {
+        let hotkey = match self.last_press {
+            Some(ref mut press) if press.serial == timer.press => {
+                match press.holds.iter().position(|h| Rc::ptr_eq(h, &timer.hotkey)) {
+                    Some(i) => press.holds.remove(i),
+                    None => return Default::default(),
+                }
             },
+            _ => return Default::default(),
+        };
+
+        if !hotkey.keys().all(|k| self.keys.get(k)) {
+            return Default::default()
         }
+
+        let mut res: Vec<_> = hotkey.events.iter().cloned()
+            .map(ProcessedInputEvent::HotkeyEvent)
+            .collect();
+        if hotkey.consume {
+            // the trigger has already reached the guest, but its modifiers can still be taken back
+            let key = self.last_press.as_ref().map(|p| p.key);
+            let releases = self.release_modifiers(&[hotkey], key);
+            res.extend(releases.into_iter().map(ProcessedInputEvent::InputEvent));
+        }
+
+        res
+    }
+
+    fn release_modifiers(&mut self, hotkeys: &[Rc<Hotkey<U>>], key: Option<Key>) -> Vec<InputEvent> {
+        let modifiers: Vec<_> = hotkeys.iter()
+            .filter(|h| h.consume)
+            .flat_map(|h| h.modifiers.iter().cloned())
+            .filter(|&k| Some(k) != key && self.keys.get(k) && !self.consumed.get(k))
+            .collect();
+
+        let mut res = Vec::new();
+        if !modifiers.is_empty() {
+            for modifier in modifiers {
+                self.consumed.set(modifier);
+                let release = KeyEvent::new(Default::default(), modifier, Self::key_state(false)).into();
+                res.extend(self.map_input_event(release));
+            }
+            res.push(Self::sync_report());
+        }
+
+        res
     }
 
-    fn process_input_event_(&mut self, e: EventRef) -> Vec<Rc<U>> {
+    fn process_input_event_(&mut self, e: EventRef, source: TimeSource) -> Vec<Rc<Hotkey<U>>> {
         match e {
+            EventRef::Relative(rel) => match Self::rel_wheel(rel.axis, rel.value) {
+                Some(wheel) => self.process_wheel(wheel),
+                None => Default::default(),
+            },
             EventRef::Key(key) => {
                 let state = key.key_state();
+                let time = Self::event_time(&key.time);
 
-                let hotkeys = match state {
-                    KeyState::Pressed => self.triggers_press.get(&key.key),
-                    KeyState::Released => self.triggers_release.get(&key.key),
-                    _ => None,
+                let (mut events, held, double_tap) = match state {
+                    KeyState::Pressed => {
+                        self.keys.set(key.key);
+                        let double_tap = match self.last_tap.take() {
+                            Some(tap) if tap.key == key.key && tap.source == source =>
+                                time.checked_sub(tap.released).map(|gap| (tap.held, gap)),
+                            _ => None,
+                        };
+                        self.presses += 1;
+                        self.last_press = Some(Press {
+                            key: key.key,
+                            time: time,
+                            source: source,
+                            serial: self.presses,
+                            holds: Vec::new(),
+                        });
+                        (self.process_sequences(key.key, time), None, double_tap)
+                    },
+                    KeyState::Released => {
+                        // only counts if nothing else was pressed in the meantime
+                        let (held, holds) = match self.last_press.take() {
+                            Some(press) if press.key == key.key && press.source == source =>
+                                (time.checked_sub(press.time), press.holds),
+                            _ => (None, Vec::new()),
+                        };
+                        if let Some(held) = held {
+                            self.last_tap = Some(Tap {
+                                key: key.key,
+                                source: source,
+                                held: held,
+                                released: time,
+                            });
+                        }
+                        // holds whose timer hasn't gotten to them yet
+                        let keys = &self.keys;
+                        let holds = holds.into_iter()
+                            .filter(|h| h.keys().all(|k| keys.get(k)))
+                            .filter(|h| held.map(|held| held > h.threshold).unwrap_or(false))
+                            .collect();
+                        (holds, held, None)
+                    },
+                    _ => (Vec::new(), None, None),
                 };
 
-                match state {
-                    KeyState::Pressed => self.keys.set(key.key),
-                    _ => (),
-                }
+                let key_keysyms = self.key_keysyms(key.key);
+                let hotkeys: Vec<_> = {
+                    let (triggers, keysym_triggers) = match state {
+                        KeyState::Pressed => (self.triggers_press.get(&key.key), &self.keysym_triggers_press),
+                        KeyState::Released => (self.triggers_release.get(&key.key), &self.keysym_triggers_release),
+                        _ => (None, &self.keysym_triggers_press),
+                    };
+                    let keysym_triggers = if state == KeyState::Pressed || state == KeyState::Released {
+                        key_keysyms.iter().filter_map(|keysym| keysym_triggers.get(keysym)).collect()
+                    } else {
+                        Vec::new()
+                    };
 
-                let events = if let Some(hotkeys) = hotkeys {
-                    hotkeys.iter()
-                        .filter(|h| h.keys().all(|k| self.keys.get(k)))
-                        .filter(|h| h.triggers.contains(&key.key))
-                        .flat_map(|h| h.events.iter().cloned())
-                        .collect()
-                } else {
-                    Default::default()
+                    let mut hotkeys: Vec<Rc<Hotkey<U>>> = Vec::new();
+                    for hotkey in triggers.into_iter().chain(keysym_triggers).flat_map(|h| h.iter()) {
+                        if !hotkeys.iter().any(|h| Rc::ptr_eq(h, hotkey)) {
+                            hotkeys.push(hotkey.clone());
+                        }
+                    }
+                    hotkeys
                 };
 
+                {
+                    let keys = &self.keys;
+                    let hotkeys: Vec<_> = hotkeys.iter()
+                        .filter(|h| h.keys().all(|k| keys.get(k)))
+                        .filter(|h| h.triggers.contains(&key.key) || key_keysyms.iter().any(|k| h.keysyms.contains(k)))
+                        .collect();
+                    if let Some(ref mut press) = self.last_press {
+                        if state == KeyState::Pressed {
+                            press.holds.extend(hotkeys.iter().filter(|h| h.mode == HotkeyMode::Hold).map(|&h| h.clone()));
+                        }
+                    }
+                    let hotkeys = hotkeys.into_iter()
+                        .filter(|h| match h.mode {
+                            HotkeyMode::Normal => true,
+                            HotkeyMode::Tap => held.map(|held| held <= h.threshold).unwrap_or(false),
+                            HotkeyMode::Hold => false,
+                            HotkeyMode::DoubleTap => double_tap
+                                .map(|(held, gap)| held <= h.threshold && gap <= h.threshold)
+                                .unwrap_or(false),
+                        });
+                    // sequences completed by this press, which shouldn't start over on it
+                    let completed = events.len();
+                    for hotkey in hotkeys {
+                        if hotkey.sequence.is_empty() {
+                            events.push(hotkey.clone());
+                        } else if !self.sequences.iter().any(|s| Rc::ptr_eq(&s.hotkey, hotkey)) &&
+                            !events[..completed].iter().any(|h| Rc::ptr_eq(h, hotkey)) {
+                            self.sequences.push(PendingSequence {
+                                hotkey: hotkey.clone(),
+                                step: 0,
+                                deadline: time + hotkey.timeout,
+                            });
+                        }
+                    }
+                }
+
                 match state {
                     KeyState::Pressed => (),
                     KeyState::Released => self.keys.clear(key.key),
@@ -181,8 +866,51 @@ impl<U> Events<U> {
         }
     }
 
+    fn process_sequences(&mut self, key: Key, now: Duration) -> Vec<Rc<Hotkey<U>>> {
+        let keys = &self.keys;
+        let mut events = Vec::new();
+
+        for mut pending in mem::replace(&mut self.sequences, Vec::new()) {
+            if pending.deadline < now {
+                continue
+            }
+
+            let (matched, modifier) = {
+                let step = &pending.hotkey.sequence[pending.step];
+                (step.triggers.contains(&key) && step.keys().all(|k| keys.get(k)), step.modifiers.contains(&key))
+            };
+
+            if matched {
+                pending.step += 1;
+                if pending.step == pending.hotkey.sequence.len() {
+                    events.push(pending.hotkey);
+                } else {
+                    pending.deadline = now + pending.hotkey.timeout;
+                    self.sequences.push(pending);
+                }
+            } else if modifier {
+                // still working towards the next chord
+                self.sequences.push(pending);
+            }
+        }
+
+        events
+    }
+
+    fn event_time(time: &EventTime) -> Duration {
+        Duration::new(time.seconds() as u64, time.microseconds() as u32 * 1000)
+    }
+
+    fn x_time(time: xcb::Timestamp) -> EventTime {
+        EventTime::new((time / 1000) as _, (time % 1000 * 1000) as _)
+    }
+
     fn sync_report() -> InputEvent {
-        SynchronizeEvent::new(Default::default(), SynchronizeKind::Report, 0).into()
+        Self::sync_report_at(Default::default())
+    }
+
+    fn sync_report_at(time: EventTime) -> InputEvent {
+        SynchronizeEvent::new(time, SynchronizeKind::Report, 0).into()
     }
 
     fn key_state(pressed: bool) -> i32 {
@@ -192,14 +920,27 @@ impl<U> Events<U> {
         }
     }
 
-    fn key_event(key: Key, pressed: bool) -> Vec<ProcessedXEvent> {
+    fn key_event(key: Key, pressed: bool, time: xcb::Timestamp) -> Vec<ProcessedXEvent<U>> {
+        vec![
+            KeyEvent::new(Self::x_time(time), key, Self::key_state(pressed)).into(),
+            Self::sync_report_at(Self::x_time(time)).into(),
+        ]
+    }
+
+    fn wheel_event(wheel: Wheel, time: xcb::Timestamp) -> Vec<ProcessedXEvent<U>> {
+        let (axis, value) = match wheel {
+            Wheel::Up => (RelativeAxis::Wheel, 1),
+            Wheel::Down => (RelativeAxis::Wheel, -1),
+            Wheel::Left => (RelativeAxis::HorizontalWheel, -1),
+            Wheel::Right => (RelativeAxis::HorizontalWheel, 1),
+        };
         vec![
-            KeyEvent::new(Default::default(), key, Self::key_state(pressed)).into(),
-            Self::sync_report().into(),
+            ProcessedXEvent::RelativeEvent(RelativeEvent::new(Self::x_time(time), axis, value).into()),
+            ProcessedXEvent::RelativeEvent(Self::sync_report_at(Self::x_time(time))),
         ]
     }
 
-    pub fn process_x_event(&mut self, e: &XEvent) -> Vec<ProcessedXEvent> {
+    pub fn process_x_event(&mut self, e: &XEvent) -> Vec<ProcessedXEvent<U>> {
         match *e {
             XEvent::State(state) => {
                 self.xstate = state;
@@ -213,17 +954,24 @@ impl<U> Events<U> {
             XEvent::Focus(focus) => if !focus {
                 vec![UserEvent::UnstickGuest.into()] // TODO: wtf just generate the events here!!
             } else {
+                self.lock_sync_events()
+            },
+            XEvent::LockState(locks) => {
+                self.host_locks = locks;
                 Default::default()
             },
             XEvent::UnstickGuest => {
                 let res = self.unstick_events().map(From::from).collect();
                 self.keys = Default::default();
+                self.consumed = Default::default();
                 res
             },
-            XEvent::Mouse { x, y } => {
-                let events = [
-                    (self.xstate.width, self.mouse_x, x, AbsoluteAxis::X),
-                    (self.xstate.height, self.mouse_y, y, AbsoluteAxis::Y),
+            XEvent::Mouse { x, y, time } => {
+                let (x_offset, y_offset, width, height) = self.viewport();
+                let time = Self::x_time(time);
+                let mut events: Vec<_> = [
+                    (width, self.mouse_x, x - x_offset, AbsoluteAxis::X),
+                    (height, self.mouse_y, y - y_offset, AbsoluteAxis::Y),
                 ].iter()
                     .filter(|&&(dim, old, new, _)| old != new && dim != 0)
                     .map(|&(dim, _, new, axis)| (
@@ -237,34 +985,64 @@ impl<U> Events<U> {
                         },
                         axis
                     )).map(|(dim, new, axis)| AbsoluteEvent::new(
-                        Default::default(),
+                        time,
                         axis,
                         new as i32 * 0x8000 / dim as i32,
                     ).into())
-                    .chain(iter::once(Self::sync_report().into()))
                     .collect();
+                // nothing to report if the position didn't change
+                if !events.is_empty() {
+                    events.push(Self::sync_report_at(time).into());
+                }
 
-                self.mouse_x = x;
-                self.mouse_y = y;
+                self.mouse_x = x - x_offset;
+                self.mouse_y = y - y_offset;
 
                 events
             },
-            XEvent::Button { pressed, button, .. } => {
-                if let Some(button) = self.x_button(button) {
-                    Self::key_event(button, pressed)
-                } else {
+            XEvent::MouseRelative { x, y, time } => [(RelativeAxis::X, x), (RelativeAxis::Y, y)].iter()
+                .filter(|&&(_, value)| value != 0)
+                .map(|&(axis, value)| ProcessedXEvent::RelativeEvent(RelativeEvent::new(Self::x_time(time), axis, value).into()))
+                .chain(iter::once(ProcessedXEvent::RelativeEvent(Self::sync_report_at(Self::x_time(time)))))
+                .collect(),
+            XEvent::Button { pressed, button, time, .. } => match self.x_button(button) {
+                Some(XButton::Button(key)) => Self::key_event(key, pressed, time),
+                // X reports each scroll step as a click
+                Some(XButton::Wheel(wheel)) if pressed => Self::wheel_event(wheel, time),
+                Some(XButton::Wheel(..)) => Default::default(),
+                None => {
                     warn!("unknown X button {}", button);
                     Default::default()
-                }
+                },
+            },
+            XEvent::Selection(ref text) => vec![ProcessedXEvent::Clipboard(text.clone())],
+            XEvent::Keymap { ref keysyms, keysyms_per_keycode } => {
+                self.keymap = keysyms.clone();
+                self.keysyms_per_keycode = keysyms_per_keycode as _;
+                Default::default()
             },
-            XEvent::Key { pressed, keycode, keysym, .. } => {
-                if let Some(key) = self.x_keycode(keycode) { // TODO: keysym?
-                    Self::key_event(key, pressed)
+            XEvent::Key { pressed, keycode, keysym, time, .. } => {
+                let translated = if self.translate_layout {
+                    keysym.and_then(|keysym| self.x_keysym(keysym))
+                } else {
+                    None
+                };
+                if let Some(key) = translated.or_else(|| self.x_keycode(keycode)) {
+                    Self::key_event(key, pressed, time)
                 } else {
                     warn!("unknown X keycode {} keysym {:?}", keycode, keysym);
                     Default::default()
                 }
             },
+            XEvent::Hotkey { id, pressed } => match self.global_hotkeys.get(id) {
+                Some(&(ref hotkey, on_press)) if on_press == pressed =>
+                    hotkey.events.iter().cloned().map(ProcessedXEvent::HotkeyEvent).collect(),
+                Some(..) => Default::default(),
+                None => {
+                    warn!("unknown global hotkey {}", id);
+                    Default::default()
+                },
+            },
         }
     }
 
@@ -277,3 +1055,210 @@ impl<U> Events<U> {
             .chain(iter::once(Self::sync_report()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use input::{InputEvent, KeyEvent, RelativeEvent, RelativeAxis, LedEvent, LedKind, EventTime, Key, KeyState};
+    use x::XEvent;
+    use super::{Events, Hotkey, HotkeyStep, HotkeyMode, HoldTimer, TimeSource, Wheel, ProcessedInputEvent, ProcessedXEvent};
+
+    fn key(key: Key, pressed: bool, ms: u64) -> InputEvent {
+        let state = if pressed { KeyState::Pressed } else { KeyState::Released };
+        KeyEvent::new(EventTime::new((ms / 1000) as _, (ms % 1000 * 1000) as _), key, state.into()).into()
+    }
+
+    // presses and releases the key, returning the hotkeys fired by either
+    fn tap(events: &mut Events<&'static str>, k: Key, ms: u64) -> Vec<&'static str> {
+        let mut fired = press(events, k, ms);
+        fired.extend(release(events, k, ms + 10));
+        fired
+    }
+
+    fn press(events: &mut Events<&'static str>, k: Key, ms: u64) -> Vec<&'static str> {
+        hotkeys(events.process_input_event(key(k, true, ms), TimeSource::Evdev))
+    }
+
+    fn release(events: &mut Events<&'static str>, k: Key, ms: u64) -> Vec<&'static str> {
+        hotkeys(events.process_input_event(key(k, false, ms), TimeSource::Evdev))
+    }
+
+    fn hotkeys(processed: Vec<ProcessedInputEvent<&'static str>>) -> Vec<&'static str> {
+        processed.into_iter().filter_map(|e| match e {
+            ProcessedInputEvent::HotkeyEvent(e) => Some(*e),
+            _ => None,
+        }).collect()
+    }
+
+    fn hold_timers(processed: Vec<ProcessedInputEvent<&'static str>>) -> Vec<HoldTimer<&'static str>> {
+        processed.into_iter().filter_map(|e| match e {
+            ProcessedInputEvent::HoldTimer(timer) => Some(timer),
+            _ => None,
+        }).collect()
+    }
+
+    fn mode_events(mode: HotkeyMode) -> Events<&'static str> {
+        let mut events = Events::new();
+        events.add_hotkey(
+            Hotkey::new(vec![Key::KeyCapsLock], vec![], vec!["hotkey"])
+                .mode(mode, Duration::from_millis(200)),
+            true
+        );
+        events
+    }
+
+    fn sequence_events(sequence: Vec<HotkeyStep>) -> Events<&'static str> {
+        let mut events = Events::new();
+        events.add_hotkey(
+            Hotkey::new(vec![Key::KeyScrollLock], vec![], vec!["sequence"])
+                .sequence(sequence, Duration::from_millis(500)),
+            true
+        );
+        events
+    }
+
+    #[test]
+    fn sequence_repeated_trigger() {
+        let mut events = sequence_events(vec![HotkeyStep::new(vec![Key::KeyScrollLock], vec![])]);
+        assert!(tap(&mut events, Key::KeyScrollLock, 0).is_empty());
+        assert_eq!(tap(&mut events, Key::KeyScrollLock, 100), vec!["sequence"]);
+        // the completing press doesn't arm the sequence again
+        assert!(tap(&mut events, Key::KeyScrollLock, 200).is_empty());
+        assert_eq!(tap(&mut events, Key::KeyScrollLock, 300), vec!["sequence"]);
+    }
+
+    #[test]
+    fn sequence_deadline() {
+        let mut events = sequence_events(vec![HotkeyStep::new(vec![Key::KeyA], vec![])]);
+        assert!(tap(&mut events, Key::KeyScrollLock, 0).is_empty());
+        assert!(tap(&mut events, Key::KeyA, 1000).is_empty());
+
+        assert!(tap(&mut events, Key::KeyScrollLock, 2000).is_empty());
+        assert_eq!(tap(&mut events, Key::KeyA, 2400), vec!["sequence"]);
+    }
+
+    #[test]
+    fn sequence_modifiers() {
+        let mut events = sequence_events(vec![HotkeyStep::new(vec![Key::KeyA], vec![Key::KeyLeftCtrl])]);
+        assert!(tap(&mut events, Key::KeyScrollLock, 0).is_empty());
+        // the step's modifier keeps the sequence going, but its trigger alone doesn't complete it
+        assert!(press(&mut events, Key::KeyLeftCtrl, 100).is_empty());
+        assert_eq!(tap(&mut events, Key::KeyA, 150), vec!["sequence"]);
+        assert!(release(&mut events, Key::KeyLeftCtrl, 200).is_empty());
+
+        assert!(tap(&mut events, Key::KeyScrollLock, 1000).is_empty());
+        assert!(tap(&mut events, Key::KeyA, 1100).is_empty());
+        // any other key ends the sequence
+        assert!(tap(&mut events, Key::KeyScrollLock, 2000).is_empty());
+        assert!(tap(&mut events, Key::KeyB, 2100).is_empty());
+        assert!(press(&mut events, Key::KeyLeftCtrl, 2200).is_empty());
+        assert!(tap(&mut events, Key::KeyA, 2250).is_empty());
+    }
+
+    #[test]
+    fn tap_mode() {
+        let mut events = mode_events(HotkeyMode::Tap);
+        assert!(press(&mut events, Key::KeyCapsLock, 0).is_empty());
+        assert_eq!(release(&mut events, Key::KeyCapsLock, 100), vec!["hotkey"]);
+
+        assert!(press(&mut events, Key::KeyCapsLock, 1000).is_empty());
+        assert!(release(&mut events, Key::KeyCapsLock, 1300).is_empty());
+
+        // something else was pressed in the meantime
+        assert!(press(&mut events, Key::KeyCapsLock, 2000).is_empty());
+        assert!(tap(&mut events, Key::KeyA, 2050).is_empty());
+        assert!(release(&mut events, Key::KeyCapsLock, 2100).is_empty());
+    }
+
+    #[test]
+    fn hold_mode() {
+        let mut events = mode_events(HotkeyMode::Hold);
+        let timers = hold_timers(events.process_input_event(key(Key::KeyCapsLock, true, 0), TimeSource::Evdev));
+        assert_eq!(timers.len(), 1);
+        assert_eq!(timers[0].delay(), Duration::from_millis(200));
+        // fires while the key is still held, and only once
+        assert_eq!(hotkeys(events.process_hold(timers.into_iter().next().unwrap())), vec!["hotkey"]);
+        assert!(release(&mut events, Key::KeyCapsLock, 500).is_empty());
+
+        // released before the timer expired
+        let timers = hold_timers(events.process_input_event(key(Key::KeyCapsLock, true, 1000), TimeSource::Evdev));
+        assert!(release(&mut events, Key::KeyCapsLock, 1100).is_empty());
+        assert!(hotkeys(events.process_hold(timers.into_iter().next().unwrap())).is_empty());
+
+        // something else was pressed before the timer expired
+        let timers = hold_timers(events.process_input_event(key(Key::KeyCapsLock, true, 2000), TimeSource::Evdev));
+        assert!(tap(&mut events, Key::KeyA, 2050).is_empty());
+        assert!(hotkeys(events.process_hold(timers.into_iter().next().unwrap())).is_empty());
+        assert!(release(&mut events, Key::KeyCapsLock, 2500).is_empty());
+    }
+
+    #[test]
+    fn hold_late_timer() {
+        let mut events = mode_events(HotkeyMode::Hold);
+        let timers = hold_timers(events.process_input_event(key(Key::KeyCapsLock, true, 0), TimeSource::Evdev));
+        // the release beat the timer but came after the threshold
+        assert_eq!(release(&mut events, Key::KeyCapsLock, 300), vec!["hotkey"]);
+        assert!(hotkeys(events.process_hold(timers.into_iter().next().unwrap())).is_empty());
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut events = mode_events(HotkeyMode::DoubleTap);
+        assert!(tap(&mut events, Key::KeyCapsLock, 0).is_empty());
+        assert_eq!(tap(&mut events, Key::KeyCapsLock, 100), vec!["hotkey"]);
+
+        // too long between taps
+        assert!(tap(&mut events, Key::KeyCapsLock, 1000).is_empty());
+        assert!(tap(&mut events, Key::KeyCapsLock, 1500).is_empty());
+
+        // the first tap was held too long
+        assert!(press(&mut events, Key::KeyCapsLock, 3000).is_empty());
+        assert!(release(&mut events, Key::KeyCapsLock, 3300).is_empty());
+        assert!(tap(&mut events, Key::KeyCapsLock, 3350).is_empty());
+    }
+
+    #[test]
+    fn double_tap_time_sources() {
+        let mut events = mode_events(HotkeyMode::DoubleTap);
+        assert!(tap(&mut events, Key::KeyCapsLock, 1000).is_empty());
+        // X time is unrelated to evdev time, even if it happens to look close
+        assert!(hotkeys(events.process_input_event(key(Key::KeyCapsLock, true, 1050), TimeSource::X)).is_empty());
+    }
+
+    #[test]
+    fn wheel() {
+        let mut events = Events::new();
+        events.add_hotkey(Hotkey::new(vec![Key::ButtonSide], vec![], vec!["wheel"]).wheel(vec![Wheel::Up]), true);
+        let scroll = |events: &mut Events<&'static str>, value| hotkeys(events.process_input_event(
+            RelativeEvent::new(EventTime::new(0, 0), RelativeAxis::Wheel, value).into(), TimeSource::Evdev
+        ));
+
+        assert!(scroll(&mut events, 1).is_empty());
+        // the trigger alone doesn't fire it
+        assert!(press(&mut events, Key::ButtonSide, 0).is_empty());
+        assert_eq!(scroll(&mut events, 1), vec!["wheel"]);
+        assert!(scroll(&mut events, -1).is_empty());
+        assert!(release(&mut events, Key::ButtonSide, 100).is_empty());
+        assert!(scroll(&mut events, 1).is_empty());
+    }
+
+    #[test]
+    fn lock_sync() {
+        for &mode in &[HotkeyMode::Normal, HotkeyMode::Tap] {
+            let mut events = mode_events(mode);
+            events.set_sync_locks(true);
+            // the guest turned caps lock on while the host's is off
+            let led = LedEvent::new(Default::default(), LedKind::CapsLock, 1).into();
+            assert!(events.process_led_event(&led).is_some());
+
+            let synced = events.process_x_event(&XEvent::Focus(true));
+            assert_eq!(synced.len(), 4);
+            assert!(synced.iter().all(|e| match *e {
+                ProcessedXEvent::LockSync(..) => true,
+                _ => false,
+            }));
+            // only the user's own caps lock fires the hotkey
+            assert_eq!(tap(&mut events, Key::KeyCapsLock, 1000), vec!["hotkey"]);
+        }
+    }
+}
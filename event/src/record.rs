@@ -0,0 +1,228 @@
+// Recordings are plain text with one event per line:
+//
+//     <elapsed> <sink> <in|out> <type> <code> <value> <time>
+//
+// `elapsed` is the seconds.microseconds since the recording started, `sink` is
+// the uinput device the event was headed for (`abs` or `rel`), and `in` marks
+// events as they were received from X or an evdev grab while `out` marks those
+// actually written to the device after hotkeys and remapping were applied.
+// `type`, `code` and `value` are the raw evdev numbers, and `time` is the
+// event's own timestamp. Blank lines and lines starting with `#` are ignored.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use std::str::FromStr;
+use std::fmt;
+use input::{InputEvent, EventKind, EventTime};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedEvent {
+    pub elapsed: Duration,
+    pub sink: String,
+    pub direction: Direction,
+    pub event: InputEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid recorded event: {}", self.0)
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        })
+    }
+}
+
+impl fmt::Display for RecordedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:06} {} {} {} {} {} {}.{:06}",
+            self.elapsed.as_secs(), self.elapsed.subsec_nanos() / 1000,
+            self.sink, self.direction,
+            self.event.kind as u16, self.event.code, self.event.value,
+            self.event.time.seconds(), self.event.time.microseconds(),
+        )
+    }
+}
+
+impl RecordedEvent {
+    // returns None for blank and comment lines
+    pub fn parse(line: &str) -> Result<Option<Self>, ParseError> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None)
+        }
+
+        let err = || ParseError(line.into());
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() != 7 {
+            return Err(err())
+        }
+
+        let (elapsed_secs, elapsed_usecs) = parse_time(fields[0]).ok_or_else(err)?;
+        let direction = match fields[2] {
+            "in" => Direction::In,
+            "out" => Direction::Out,
+            _ => return Err(err()),
+        };
+        let kind = u16::from_str(fields[3]).ok()
+            .and_then(|kind| EventKind::from_type(kind).ok())
+            .ok_or_else(err)?;
+        let code = u16::from_str(fields[4]).map_err(|_| err())?;
+        let value = i32::from_str(fields[5]).map_err(|_| err())?;
+        let (secs, usecs) = parse_time(fields[6]).ok_or_else(err)?;
+
+        Ok(Some(RecordedEvent {
+            elapsed: Duration::new(elapsed_secs as u64, elapsed_usecs as u32 * 1000),
+            sink: fields[1].into(),
+            direction: direction,
+            event: InputEvent {
+                time: EventTime::new(secs as _, usecs as _),
+                kind: kind,
+                code: code,
+                value: value,
+            },
+        }))
+    }
+}
+
+fn parse_time(s: &str) -> Option<(i64, i64)> {
+    let mut parts = s.splitn(2, '.');
+    let secs = parts.next().and_then(|s| i64::from_str(s).ok());
+    let usecs = parts.next().and_then(|s| i64::from_str(s).ok());
+    match (secs, usecs) {
+        (Some(secs), Some(usecs)) if secs >= 0 && usecs >= 0 && usecs < 1000000 => Some((secs, usecs)),
+        _ => None,
+    }
+}
+
+// output is expected to be buffered, and only reaches it once flushed
+pub struct Recorder<W> {
+    start: Instant,
+    output: W,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut output: W) -> io::Result<Self> {
+        writeln!(output, "# screenstub recording: <elapsed> <sink> <in|out> <type> <code> <value> <time>")?;
+
+        Ok(Recorder {
+            start: Instant::now(),
+            output: output,
+        })
+    }
+
+    pub fn record(&mut self, sink: &str, direction: Direction, event: &InputEvent) -> io::Result<()> {
+        let e = RecordedEvent {
+            elapsed: self.start.elapsed(),
+            sink: sink.into(),
+            direction: direction,
+            event: *event,
+        };
+        writeln!(self.output, "{}", e)
+    }
+
+    pub fn comment(&mut self, comment: &str) -> io::Result<()> {
+        writeln!(self.output, "# {}", comment)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use input::{InputEvent, EventKind, EventTime};
+    use super::{RecordedEvent, Recorder, Direction};
+
+    fn event() -> RecordedEvent {
+        RecordedEvent {
+            elapsed: Duration::new(12, 345678000),
+            sink: "abs".into(),
+            direction: Direction::Out,
+            event: InputEvent {
+                time: EventTime::new(1500000000, 42),
+                kind: EventKind::Key,
+                code: 30,
+                value: 1,
+            },
+        }
+    }
+
+    fn assert_same(a: &RecordedEvent, b: &RecordedEvent) {
+        assert_eq!(a.elapsed, b.elapsed);
+        assert_eq!(a.sink, b.sink);
+        assert_eq!(a.direction, b.direction);
+        assert_eq!(a.event.kind, b.event.kind);
+        assert_eq!((a.event.code, a.event.value), (b.event.code, b.event.value));
+        assert_eq!(
+            (a.event.time.seconds(), a.event.time.microseconds()),
+            (b.event.time.seconds(), b.event.time.microseconds())
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let e = event();
+        let line = e.to_string();
+        assert_eq!(line, "12.345678 abs out 1 30 1 1500000000.000042");
+        assert_same(&RecordedEvent::parse(&line).unwrap().unwrap(), &e);
+    }
+
+    #[test]
+    fn parse_ignored() {
+        assert!(RecordedEvent::parse("").unwrap().is_none());
+        assert!(RecordedEvent::parse("  ").unwrap().is_none());
+        assert!(RecordedEvent::parse("# hotkey ToggleShow").unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for line in &[
+            "12.345678 abs out 1 30 1",
+            "12.345678 abs sideways 1 30 1 0.0",
+            "12.345678 abs out 1 30 x 0.0",
+            "12.1000000 abs out 1 30 1 0.0",
+            "12 abs out 1 30 1 0.0",
+        ] {
+            assert!(RecordedEvent::parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn recorder() {
+        let mut output = Vec::new();
+        {
+            let mut recorder = Recorder::new(&mut output).unwrap();
+            recorder.record("rel", Direction::In, &event().event).unwrap();
+            recorder.comment("hotkey ToggleShow").unwrap();
+            recorder.flush().unwrap();
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        let events: Vec<_> = output.lines()
+            .map(|line| RecordedEvent::parse(line).unwrap())
+            .filter_map(|e| e)
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].sink, "rel");
+        assert_eq!(events[0].direction, Direction::In);
+        assert_eq!((events[0].event.code, events[0].event.value), (30, 1));
+        assert_eq!(output.lines().count(), 3);
+    }
+}
@@ -6,6 +6,9 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate base64 as b64;
 
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, error};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
@@ -81,6 +84,18 @@ pub struct QapiError {
     pub desc: String,
 }
 
+impl fmt::Display for QapiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QAPI error: {}", self.desc)
+    }
+}
+
+impl error::Error for QapiError {
+    fn description(&self) -> &str {
+        &self.desc
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum QapiResponse<C: QapiCommand> {
@@ -152,3 +167,159 @@ impl QapiCommand for GuestExecStatus {
 
     const NAME: &'static str = "guest-exec-status";
 }
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GuestSync {
+    pub id: u32,
+}
+
+impl QapiCommand for GuestSync {
+    type Ok = u32;
+
+    const NAME: &'static str = "guest-sync";
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GuestInfo {
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuestInfoResponse {
+    pub version: String,
+}
+
+impl QapiCommand for GuestInfo {
+    type Ok = GuestInfoResponse;
+
+    const NAME: &'static str = "guest-info";
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GuestShutdown {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+}
+
+// the agent only replies if the shutdown fails
+impl QapiCommand for GuestShutdown {
+    type Ok = ();
+
+    const NAME: &'static str = "guest-shutdown";
+}
+
+#[derive(Serialize)]
+struct QapiRequest<'a, C: 'a> {
+    execute: &'static str,
+    arguments: &'a C,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum QapiReply<T> {
+    Return(T),
+    Error(QapiError),
+}
+
+#[derive(Debug)]
+pub enum QgaError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Qapi(QapiError),
+}
+
+impl From<io::Error> for QgaError {
+    fn from(e: io::Error) -> Self {
+        QgaError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for QgaError {
+    fn from(e: serde_json::Error) -> Self {
+        QgaError::Json(e)
+    }
+}
+
+impl From<QapiError> for QgaError {
+    fn from(e: QapiError) -> Self {
+        QgaError::Qapi(e)
+    }
+}
+
+impl fmt::Display for QgaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QgaError::Io(ref e) => write!(f, "QGA I/O error: {}", e),
+            QgaError::Json(ref e) => write!(f, "QGA protocol error: {}", e),
+            QgaError::Qapi(ref e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl error::Error for QgaError {
+    fn description(&self) -> &str {
+        "QGA error"
+    }
+}
+
+// A blocking guest agent client over a socket stream
+pub struct Qga<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> Qga<S> {
+    pub fn new(stream: S) -> Self {
+        Qga {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    fn read_reply<T: DeserializeOwned>(&mut self) -> Result<QapiReply<T>, QgaError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "QGA connection closed").into())
+        }
+        trace!("QGA <- {}", line.trim());
+
+        serde_json::from_str(&line).map_err(From::from)
+    }
+
+    pub fn execute_nowait<C: QapiCommand>(&mut self, command: &C) -> Result<(), QgaError> {
+        let request = serde_json::to_string(&QapiRequest {
+            execute: C::NAME,
+            arguments: command,
+        })?;
+        trace!("QGA -> {}", request);
+
+        let stream = self.stream.get_mut();
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(b"\n")?;
+        stream.flush().map_err(From::from)
+    }
+
+    // discards any replies left over from a previous connection
+    pub fn handshake(&mut self) -> Result<(), QgaError> {
+        // needs to be unique enough to not match a stale reply
+        let id = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.subsec_nanos()).unwrap_or(0);
+        // resets the agent's parser in case a previous client left a partial request
+        self.stream.get_mut().write_all(&[0xff])?;
+        let command = GuestSync { id: id };
+        self.execute_nowait(&command)?;
+
+        loop {
+            match self.read_reply::<u32>() {
+                Ok(QapiReply::Return(reply)) if reply == id => break Ok(()),
+                Ok(..) | Err(QgaError::Json(..)) => (),
+                Err(e) => break Err(e),
+            }
+        }
+    }
+
+    pub fn execute<C: QapiCommand>(&mut self, command: C) -> Result<C::Ok, QgaError> {
+        self.execute_nowait(&command)?;
+
+        match self.read_reply::<C::Ok>()? {
+            QapiReply::Return(reply) => Ok(reply),
+            QapiReply::Error(e) => Err(e.into()),
+        }
+    }
+}
@@ -11,27 +11,31 @@ extern crate screenstub_config as config;
 extern crate screenstub_event as event;
 extern crate screenstub_ddc as ddc;
 extern crate screenstub_x as x;
+extern crate screenstub_qmp as qmp;
 extern crate tokio_unzip;
 extern crate tokio_timer;
 extern crate tokio_fuse;
 extern crate tokio_core;
 extern crate tokio_process;
 extern crate serde_yaml;
+extern crate serde_json;
 extern crate result;
 extern crate clap;
 
 use std::collections::{HashMap, HashSet};
 use std::process::{exit, Command, Stdio, ExitStatus};
-use std::thread::spawn;
+use std::thread::{spawn, sleep};
 use std::cell::{Cell, RefCell};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::path::{PathBuf, Path};
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsStr;
 use std::rc::Rc;
-use std::io::{self, Write};
-use std::os::unix::ffi::OsStrExt;
+use std::mem;
+use std::io::{self, Write, BufRead, BufReader, BufWriter};
+use std::fs::File;
+use std::os::unix::net::UnixStream;
 use tokio_core::reactor::{Core, Handle};
 use tokio_unzip::StreamUnzipExt;
 use tokio_process::CommandExt;
@@ -40,21 +44,32 @@ use tokio_fuse::SharedFuse;
 use futures::sync::mpsc;
 use futures::unsync::mpsc as un_mpsc;
 use futures::{Future, Stream, Sink, IntoFuture, stream, future};
+use futures::future::Either;
 use futures_cpupool::CpuPool;
 use failure::Error;
 use result::ResultOptionExt;
 use clap::{Arg, App, SubCommand, AppSettings};
-use input::{InputId, InputEvent, RelativeAxis};
+use input::{InputId, InputEvent, EventRef, RelativeAxis, Key};
 use config::{
-    Config, ConfigEvent, ConfigGrab, ConfigGrabMode, ConfigInputEvent,
+    Config, ConfigScreen, ConfigEvent, ConfigExecCommand, ConfigGrab, ConfigGrabMode, ConfigInputEvent,
     ConfigDdc, ConfigDdcHost, ConfigDdcGuest,
     ConfigQemuDriver, ConfigQemuComm,
 };
-use event::{Hotkey, UserEvent, ProcessedXEvent};
+use event::{Hotkey, HotkeyStep, HotkeyMode, HoldTimer, TimeSource, Wheel, XButton, Remap, UserEvent, ProcessedXEvent, ProcessedInputEvent};
+use event::record::{Recorder, RecordedEvent, Direction};
 use ddc::{SearchDisplay, SearchInput};
 #[cfg(feature = "with-ddcutil")]
 use ddc::Monitor;
-use x::XRequest;
+use x::{XRequest, XEvent, XState, XSelection};
+use qmp::{Qga, GuestExec, GuestExecStatus, GuestInfo, GuestShutdown};
+use template::TemplateVars;
+use ssh::Ssh;
+
+mod template;
+mod ssh;
+
+const UINPUT_ABS_ID: &'static str = "screenstub-abs";
+const UINPUT_REL_ID: &'static str = "screenstub-rel";
 
 fn main() {
     match main_result() {
@@ -79,8 +94,34 @@ fn main_result() -> Result<i32, Error> {
             .value_name("CONFIG")
             .takes_value(true)
             .help("Configuration TOML file")
+        ).arg(Arg::with_name("profile")
+            .short("p")
+            .long("profile")
+            .value_name("PROFILE")
+            .takes_value(true)
+            .help("Named configuration profile to start with")
         ).subcommand(SubCommand::with_name("x")
             .about("Start the KVM with a fullscreen X window")
+        ).subcommand(SubCommand::with_name("record")
+            .about("Start the KVM like x, and record input events sent to the guest")
+            .arg(Arg::with_name("output")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(true)
+                .help("File to write the recording to")
+            )
+        ).subcommand(SubCommand::with_name("replay")
+            .about("Replay recorded input events")
+            .arg(Arg::with_name("uinput")
+                .short("u")
+                .long("uinput")
+                .help("Write the recorded output events to new uinput devices instead of checking them")
+            ).arg(Arg::with_name("recording")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(true)
+                .help("Recording to replay")
+            )
         ).subcommand(SubCommand::with_name("detect")
             .about("Detect available DDC/CI displays and their video inputs")
         ).subcommand(SubCommand::with_name("input")
@@ -96,27 +137,53 @@ fn main_result() -> Result<i32, Error> {
                 .possible_values(&["host", "guest"])
                 .help("Switch to either the host or guest monitor input")
             )
+        ).subcommand(SubCommand::with_name("config")
+            .about("Configuration file utilities")
+            .subcommand(SubCommand::with_name("schema")
+                .about("Print a JSON Schema describing the configuration format")
+            ).setting(AppSettings::SubcommandRequiredElseHelp)
         ).setting(AppSettings::SubcommandRequiredElseHelp);
 
     let matches = app.get_matches();
-    let config = if let Some(config) = matches.value_of("config") {
-        use std::fs::File;
-
+    let config: Config = if let Some(config) = matches.value_of("config") {
         let mut f = File::open(config)?;
         serde_yaml::from_reader(f)?
     } else {
         Config::default()
     };
+    for screen in &config {
+        check_screen(screen)?;
+    }
+
+    let profile = matches.value_of("profile");
 
     match matches.subcommand() {
-        ("x", Some(..)) => {
-            let config = config.get(0).ok_or_else(|| format_err!("expected a screen config"))?.clone();
+        ("x", Some(matches)) | ("record", Some(matches)) => {
+            let screen = config.get(0).ok_or_else(|| format_err!("expected a screen config"))?.clone();
+            let (config, profile) = screen_profile(&screen, profile)?;
+
+            let recorder = matches.value_of("output")
+                .map(|path| File::create(path).map(BufWriter::new).and_then(Recorder::new))
+                .invert()?
+                .map(|r| Rc::new(RefCell::new(r)));
 
             let (mut x_sender, x_receiver) = mpsc::channel(0x20); // TODO: up this after testing that backpressure works
             let (xreq_sender, xreq_receiver) = mpsc::channel(0x08);
+            let window_config = x::XWindowConfig {
+                windowed: config.window.windowed,
+                width: config.window.width,
+                height: config.window.height,
+                title: config.window.title.clone(),
+                class: config.window.class.clone(),
+                monitor: x::XMonitor {
+                    manufacturer: config.monitor.manufacturer.clone(),
+                    model: config.monitor.model.clone(),
+                    serial: config.monitor.serial.clone(),
+                },
+            };
             let xthread = spawn(move || {
-                if let Err(res) = x::XContext::xmain(xreq_receiver, &mut x_sender) {
-                    x::XContext::spin_send(&mut x_sender, Err(res))
+                if let Err(res) = x::XContext::xmain(window_config, xreq_receiver, &mut x_sender) {
+                    x_sender.send(Err(res)).wait().map(drop)
                 } else {
                     Ok(())
                 }
@@ -138,7 +205,6 @@ fn main_result() -> Result<i32, Error> {
                 .id(&uinput_id)
                 .x_config_abs().create()?;
             let uinput_abs_path = uinput_abs.path().to_owned();
-            const UINPUT_ABS_ID: &'static str = "screenstub-abs";
             info!("uinput abs path: {}", uinput_abs_path.display());
 
             let uinput_rel = uinput::Builder::new()
@@ -146,7 +212,6 @@ fn main_result() -> Result<i32, Error> {
                 .id(&uinput_id)
                 .x_config_rel().create()?;
             let uinput_rel_path = uinput_rel.path().to_owned();
-            const UINPUT_REL_ID: &'static str = "screenstub-rel";
             info!("uinput rel path: {}", uinput_rel_path.display());
 
             let mut core = Core::new()?;
@@ -157,10 +222,20 @@ fn main_result() -> Result<i32, Error> {
             let mut qemu = Rc::new(RefCell::new(Qemu::new(config.qemu, core_handle.clone())));
 
             let x_input_filter = Rc::new(RefCell::new(InputEventFilter::empty()));
-            let (input_organic_sender, input_organic_receiver) = un_mpsc::channel(0x10);
             let (input_abs_sender, input_abs_receiver) = un_mpsc::channel(0x10);
             let (input_rel_sender, input_rel_receiver) = un_mpsc::channel(0x10);
 
+            let events = screen_events(&config)?;
+
+            let x_hotkeys = events.x_hotkeys();
+            if !x_hotkeys.is_empty() {
+                core_handle.spawn(xreq_sender.clone().send(XRequest::GrabHotkeys(x_hotkeys))
+                    .map(drop).map_err(drop)
+                );
+            }
+
+            let events = Rc::new(RefCell::new(events));
+
             let mut user = UserProcess::new(core_handle.clone(),
                 ddc_pool,
                 convert_display(config.monitor),
@@ -168,20 +243,20 @@ fn main_result() -> Result<i32, Error> {
                 convert_input(config.guest_source),
                 config.ddc,
                 qemu.clone(),
-                input_organic_sender.clone(),
                 input_rel_sender.clone(),
                 x_input_filter.clone(),
                 timer.clone(),
+                events.clone(),
+                screen,
+                profile.clone(),
+                vec![(UINPUT_ABS_ID, uinput_abs_path.clone()), (UINPUT_REL_ID, uinput_rel_path.clone())],
+                xreq_sender.clone(),
             );
             let user = Rc::new(RefCell::new(user));
 
-            let mut events = event::Events::new();
-            config.hotkeys.into_iter()
-                .map(convert_hotkey)
-                .for_each(|(hotkey, on_press)| events.add_hotkey(hotkey, on_press));
-            config.key_remap.into_iter().for_each(|(from, to)| events.add_remap(from, to));
-
-            let events = Rc::new(RefCell::new(events));
+            core_handle.spawn(xreq_sender.clone().send(XRequest::SetStatus(user.borrow().status()))
+                .map(drop).map_err(drop)
+            );
 
             let evdev_sleep = timer.sleep(Duration::from_secs(2)).map_err(Error::from);
             core_handle.spawn(qemu.borrow_mut().remove_evdev(UINPUT_ABS_ID)
@@ -214,60 +289,67 @@ fn main_result() -> Result<i32, Error> {
                 .or_else(|_| Ok::<_, ()>(()))
             );
 
+            let (user_sender, user_receiver) = un_mpsc::channel::<Rc<ConfigEvent>>(0x08);
+
             let uinput_abs = uinput_abs.to_sink(&core_handle)?;
-            core_handle.spawn(input_abs_receiver
-                .map({
-                    let events = events.clone();
-                    move |e| events.borrow_mut().map_input_event(e)
-                })
-                .map_err(|_| -> Error { unreachable!() })
-                .forward(uinput_abs).map(drop).map_err(drop) // TODO: error handling
-            );
+            user.borrow_mut().add_latency("abs", uinput_abs.latency());
+            let (uinput_abs, uinput_abs_leds) = uinput_abs.split();
+            let lock_sender = spawn_input_sink(&core_handle, "abs", input_abs_receiver, events.clone(), user_sender.clone(), uinput_abs, timer.clone(), recorder.clone())?;
+            spawn_led_sync(&core_handle, uinput_abs_leds, events.clone(), xreq_sender.clone());
 
             let uinput_rel = uinput_rel.to_sink(&core_handle)?;
-            core_handle.spawn(input_rel_receiver
-                .map({
-                    let events = events.clone();
-                    move |e| events.borrow_mut().map_input_event(e)
-                })
-                .map_err(|_| -> Error { unreachable!() })
-                .forward(uinput_rel).map(drop).map_err(drop) // TODO: error handling
-            );
-
-            let (user_sender, user_receiver) = un_mpsc::channel::<Rc<ConfigEvent>>(0x08);
+            user.borrow_mut().add_latency("rel", uinput_rel.latency());
+            let (uinput_rel, uinput_rel_leds) = uinput_rel.split();
+            spawn_input_sink(&core_handle, "rel", input_rel_receiver, events.clone(), user_sender.clone(), uinput_rel, timer.clone(), recorder.clone())?;
+            spawn_led_sync(&core_handle, uinput_rel_leds, events.clone(), xreq_sender.clone());
+
+            if let Some(ref profile) = profile {
+                let grabs = config.profiles.get(profile).map(|p| p.grabs.clone()).unwrap_or_default();
+                core_handle.spawn(stream::iter_ok::<_, un_mpsc::SendError<_>>(grabs.into_iter().map(|g| Rc::new(ConfigEvent::Grab(g))))
+                    .forward(user_sender.clone()).map(drop).map_err(drop)
+                );
+            }
             core_handle.spawn(user_receiver
                 .map_err(|_| -> Error { unreachable!() })
                 .map({
                     let user = user.clone();
-                    move |userevent| stream::iter_ok::<_, Error>(user.borrow_mut().process_user_event(&userevent))
+                    move |userevent| {
+                        let mut user = user.borrow_mut();
+                        let mut events = user.process_user_event(&userevent);
+                        events.push(xreq(XRequest::SetStatus(user.status())));
+                        stream::iter_ok::<_, Error>(events)
+                    }
                 })
                 .flatten()
-                .map(|e| match e {
-                    ProcessedUserEvent::UserEvent(e) => (Some(
-                        e.or_else(|e| {
-                            warn!("UserEvent failed {} {:?}", e, e);
-                            Ok(())
-                        })
-                    ), None),
-                    ProcessedUserEvent::XRequest(e) => (None, Some(e)),
-                }).unzip_spawn(&core_handle, |s| s.filter_map(|e| e)
-                    .map_err(|_| -> mpsc::SendError<_> { unreachable!() }) // ugh come on
-                    .forward(xreq_sender).map(drop).map_err(drop)
-                ).map_err(|e| format_err!("{:?}", e))? // ugh can this even fail?
+                .map({
+                    let user = user.clone();
+                    move |e| match e {
+                        ProcessedUserEvent::UserEvent(e) => (Some({
+                            let user = user.clone();
+                            e.or_else(move |e| {
+                                warn!("UserEvent failed {} {:?}", e, e);
+                                user.borrow_mut().set_last_error(&e);
+                                Ok(())
+                            })
+                        }), None),
+                        ProcessedUserEvent::XRequest(e) => (None, Some(e)),
+                    }
+                }).unzip_spawn(&core_handle, {
+                    let xreq_sender = xreq_sender.clone();
+                    |s| s.filter_map(|e| e)
+                        .map_err(|_| -> mpsc::SendError<_> { unreachable!() }) // ugh come on
+                        .forward(xreq_sender).map(drop).map_err(drop)
+                }).map_err(|e| format_err!("{:?}", e))? // ugh can this even fail?
                 .filter_map(|e| e)
                 .buffer_unordered(8)
                 .map_err(drop)
-                .for_each(|_| Ok(()))
-            );
-
-            core_handle.spawn(input_organic_receiver
-                .map_err(|_| -> Error { unreachable!() })
-                .map({
-                    let events = events.clone();
-                    move |inputevent| stream::iter_ok::<_, Error>(events.borrow_mut().process_input_event(&inputevent))
-                }).flatten()
-                .map_err(|_| -> un_mpsc::SendError<_> { unreachable!() })
-                .forward(user_sender.clone()).map(drop).map_err(drop)
+                // user events can change the status once they complete
+                .for_each({
+                    let user = user.clone();
+                    let xreq_sender = xreq_sender.clone();
+                    move |_| xreq_sender.clone().send(XRequest::SetStatus(user.borrow().status()))
+                        .map(drop).map_err(drop)
+                })
             );
 
             core.run(x_receiver
@@ -275,21 +357,61 @@ fn main_result() -> Result<i32, Error> {
                 .and_then(|e| e)
                 .map({
                     let events = events.clone();
-                    move |xevent| stream::iter_ok::<_, Error>(events.borrow_mut().process_x_event(&xevent))
+                    let user = user.clone();
+                    move |xevent| {
+                        if let XEvent::State(ref state) = xevent {
+                            user.borrow_mut().x_state(state);
+                        }
+                        stream::iter_ok::<_, Error>(events.borrow_mut().process_x_event(&xevent))
+                    }
                 }).flatten()
-                .map(|e| match e {
-                    ProcessedXEvent::InputEvent(e) => (Some(e), None),
-                    ProcessedXEvent::UserEvent(e) => (None, Some(e)),
+                .map({
+                    let user = user.clone();
+                    let core_handle = core_handle.clone();
+                    let xreq_sender = xreq_sender.clone();
+                    move |e| match e {
+                        ProcessedXEvent::InputEvent(e) => (Some((e, false)), None),
+                        ProcessedXEvent::RelativeEvent(e) => (Some((e, true)), None),
+                        ProcessedXEvent::UserEvent(e) => (None, Some(convert_user_event(e))),
+                        ProcessedXEvent::HotkeyEvent(e) => (None, Some(e)),
+                        ProcessedXEvent::LockSync(e) => {
+                            let _ = lock_sender.unbounded_send(e);
+                            (None, None)
+                        },
+                        ProcessedXEvent::Clipboard(text) => {
+                            let future = user.borrow_mut().set_guest_clipboard(text);
+                            let user = user.clone();
+                            let xreq_sender = xreq_sender.clone();
+                            core_handle.spawn(future.or_else(move |e| {
+                                warn!("Clipboard transfer failed {} {:?}", e, e);
+                                user.borrow_mut().set_last_error(&e);
+                                let status = user.borrow().status();
+                                xreq_sender.send(XRequest::SetStatus(status)).map(drop).map_err(drop)
+                            }));
+                            (None, None)
+                        },
+                    }
                 }).unzip_spawn(&core_handle, |s| s.filter_map(|e| e)
-                    .map(convert_user_event)
                     .map_err(|_| -> un_mpsc::SendError<_> { unreachable!() }) // ugh come on
                     .forward(user_sender).map(drop).map_err(drop)
                 ).map_err(|e| format_err!("{:?}", e))? // ugh can this even fail?
                 .filter_map(|e| e)
-                .filter(|e| x_input_filter.borrow().filter_event(e))
-                .forward(input_abs_sender.fanout(input_organic_sender)).map(drop).map_err(drop)
+                .filter(|&(ref e, _)| x_input_filter.borrow().filter_x_event(e))
+                .map(|(e, rel)| if rel { (None, Some(e)) } else { (Some(e), None) })
+                .unzip_spawn(&core_handle, |s| s.filter_map(|e| e)
+                    .map_err(|_| -> un_mpsc::SendError<_> { unreachable!() })
+                    .forward(input_rel_sender).map(drop).map_err(drop)
+                ).map_err(|e| format_err!("{:?}", e))?
+                .filter_map(|e| e)
+                .forward(input_abs_sender).map(drop).map_err(drop)
             ).unwrap();
 
+            if let Some(ref recorder) = recorder {
+                if let Err(e) = recorder.borrow_mut().flush() {
+                    error!("Failed to write recording {} {:?}", e, e);
+                }
+            }
+
             if let Err(e) = core.run(qemu.borrow_mut().remove_evdev(UINPUT_ABS_ID)) {
                 error!("Failed to remove uinput device from qemu {} {:?}", e, e);
             }
@@ -312,6 +434,10 @@ fn main_result() -> Result<i32, Error> {
                 error!("Failed to run exit events: {} {:?}", e, e);
             }
 
+            if let Err(e) = core.run(user.borrow().disconnect_guest()) {
+                debug!("Failed to disconnect from guest: {} {:?}", e, e);
+            }
+
             // TODO: go back to host at this point before exiting
 
             xthread.join().unwrap()?; // TODO: get this properly
@@ -338,7 +464,8 @@ fn main_result() -> Result<i32, Error> {
         },
         #[cfg(feature = "with-ddcutil")]
         ("input", Some(matches)) => {
-            let config = config.get(0).ok_or_else(|| format_err!("expected a screen config"))?.clone();
+            let screen = config.get(0).ok_or_else(|| format_err!("expected a screen config"))?;
+            let (config, _) = screen_profile(screen, profile)?;
 
             match matches.value_of("input") {
                 Some("host") => {
@@ -360,10 +487,302 @@ fn main_result() -> Result<i32, Error> {
                 _ => unreachable!("unknown input to switch to"),
             }
         },
+        ("replay", Some(matches)) => {
+            let recording = BufReader::new(File::open(matches.value_of("recording").unwrap())?);
+            let recording = recording.lines()
+                .map(|line| line.map_err(Error::from)
+                    .and_then(|line| RecordedEvent::parse(&line).map_err(|e| format_err!("{}", e)))
+                ).filter_map(|e| match e {
+                    Ok(Some(e)) => Some(Ok(e)),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e)),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if matches.is_present("uinput") {
+                replay_uinput(recording)
+            } else {
+                let screen = config.get(0).ok_or_else(|| format_err!("expected a screen config"))?;
+                let (config, _) = screen_profile(screen, profile)?;
+                replay_events(&config, recording)
+            }
+        },
+        ("config", Some(matches)) => match matches.subcommand() {
+            ("schema", Some(..)) => {
+                let schema = config::schema::config_schema();
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+
+                Ok(0)
+            },
+            _ => unreachable!("unknown config command"),
+        },
         _ => unreachable!("unknown command"),
     }
 }
 
+fn screen_events(config: &ConfigScreen) -> Result<event::Events<ConfigEvent>, Error> {
+    let mut events = event::Events::new();
+    check_hotkeys(&config.hotkeys)?;
+    add_hotkeys(&mut events, config.hotkeys.clone());
+    convert_remaps(&config.key_remap)?.into_iter().for_each(|(from, to)| events.add_remap(from, to));
+    events.set_translate_layout(config.translate_layout);
+    events.set_aspect_ratio(config.window.aspect_ratio);
+    events.set_sync_locks(config.sync_locks);
+    config.x_buttons.iter().for_each(|(&button, &mapping)| events.set_x_button(button, Some(match mapping {
+        config::ConfigXButton::Button(key) => XButton::Button(key),
+        config::ConfigXButton::Wheel(wheel) => XButton::Wheel(convert_wheel(wheel)),
+    })));
+    for (name, layer) in &config.layers {
+        events.add_layer(name.clone(), convert_remaps(&layer.key_remap)?.into_iter().collect(), layer.hold.clone());
+    }
+
+    Ok(events)
+}
+
+// catches mistakes up front rather than when switching to a broken profile
+fn check_screen(screen: &ConfigScreen) -> Result<(), Error> {
+    screen_events(screen)?;
+    for name in screen.profiles.keys() {
+        if let Some(screen) = screen.with_profile(name) {
+            screen_events(&screen).map_err(|e| format_err!("profile {}: {}", name, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn screen_profile(screen: &ConfigScreen, profile: Option<&str>) -> Result<(ConfigScreen, Option<String>), Error> {
+    let profile = profile.map(|p| p.to_owned()).or_else(|| screen.profile.clone());
+    if let Some(profile) = profile {
+        screen.with_profile(&profile)
+            .map(|config| (config, Some(profile.clone())))
+            .ok_or_else(|| format_err!("profile {} not found", profile))
+    } else {
+        Ok((screen.clone(), None))
+    }
+}
+
+// keys only reach the abs device from X, while evdev grabs go through the rel device
+fn sink_time_source(name: &str) -> TimeSource {
+    match name {
+        "abs" => TimeSource::X,
+        _ => TimeSource::Evdev,
+    }
+}
+
+// hotkeys are processed right before reaching the guest so that they can be kept from it,
+// the returned sender writes to the device directly
+fn spawn_input_sink(handle: &Handle, name: &'static str, receiver: un_mpsc::Receiver<InputEvent>, events: Rc<RefCell<event::Events<ConfigEvent>>>, user_sender: un_mpsc::Sender<Rc<ConfigEvent>>, sink: stream::SplitSink<uinput::UInputSink>, timer: Rc<Timer>, recorder: Option<Rc<RefCell<Recorder<BufWriter<File>>>>>) -> Result<un_mpsc::UnboundedSender<InputEvent>, Error> {
+    // expired hold timers are processed in line with the input they raced against
+    let (hold_sender, hold_receiver) = un_mpsc::unbounded::<HoldTimer<ConfigEvent>>();
+    let (raw_sender, raw_receiver) = un_mpsc::unbounded::<InputEvent>();
+    let source = sink_time_source(name);
+
+    handle.spawn(receiver.map(Either::A).select(hold_receiver.map(Either::B))
+        .map_err(|_| -> Error { unreachable!() })
+        .map({
+            let recorder = recorder.clone();
+            move |e| stream::iter_ok::<_, Error>(match e {
+                Either::A(e) => {
+                    record_event(&recorder, name, Direction::In, &e);
+                    events.borrow_mut().process_input_event(e, source)
+                },
+                Either::B(hold) => events.borrow_mut().process_hold(hold),
+            })
+        })
+        .flatten()
+        .map({
+            let handle = handle.clone();
+            move |e| match e {
+                ProcessedInputEvent::InputEvent(e) => {
+                    record_event(&recorder, name, Direction::Out, &e);
+                    (Some(e), None)
+                },
+                ProcessedInputEvent::HotkeyEvent(e) => {
+                    if let Some(ref recorder) = recorder {
+                        let _ = recorder.borrow_mut().comment(&format!("hotkey {:?}", e));
+                    }
+                    (None, Some(e))
+                },
+                ProcessedInputEvent::HoldTimer(hold) => {
+                    let hold_sender = hold_sender.clone();
+                    handle.spawn(timer.sleep(hold.delay())
+                        .map_err(|e| warn!("Hold timer failed {} {:?}", e, e))
+                        .and_then(move |_| hold_sender.unbounded_send(hold).map_err(drop))
+                    );
+                    (None, None)
+                },
+            }
+        }).unzip_spawn(handle, |s| s.filter_map(|e| e)
+            .map_err(|_| -> un_mpsc::SendError<_> { unreachable!() })
+            .forward(user_sender).map(drop).map_err(drop)
+        ).map_err(|e| format_err!("{:?}", e))?
+        .filter_map(|e| e)
+        .select(raw_receiver.map_err(|_| -> Error { unreachable!() }))
+        .forward(sink).map(drop).map_err(drop) // TODO: error handling
+    );
+
+    Ok(raw_sender)
+}
+
+// QEMU writes the guest's keyboard LED state back to the uinput devices
+fn spawn_led_sync(handle: &Handle, leds: stream::SplitStream<uinput::UInputSink>, events: Rc<RefCell<event::Events<ConfigEvent>>>, xreq_sender: mpsc::Sender<XRequest>) {
+    handle.spawn(leds
+        .map_err(|e| warn!("Failed to read from uinput device {} {:?}", e, e))
+        .filter_map(move |e| events.borrow_mut().process_led_event(&e))
+        .map(XRequest::SetLockState)
+        .forward(xreq_sender.sink_map_err(drop))
+        .map(drop)
+    );
+}
+
+fn duration_ms(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_micros() as f64 / 1000.0
+}
+
+fn record_event(recorder: &Option<Rc<RefCell<Recorder<BufWriter<File>>>>>, sink: &str, direction: Direction, e: &InputEvent) {
+    if let Some(ref recorder) = *recorder {
+        if let Err(e) = recorder.borrow_mut().record(sink, direction, e) {
+            warn!("Failed to record input event {} {:?}", e, e);
+        }
+    }
+}
+
+// feeds the recorded input through the configured hotkeys and remapping, and
+// checks that the same output comes out the other side
+fn replay_events(config: &ConfigScreen, recording: Vec<RecordedEvent>) -> Result<i32, Error> {
+    let mut events = screen_events(config)?;
+    let mut expected: HashMap<String, Vec<InputEvent>> = HashMap::new();
+    let mut actual: HashMap<String, Vec<InputEvent>> = HashMap::new();
+    // hold timers expire on the recording's clock
+    let mut holds: Vec<(Duration, String, HoldTimer<ConfigEvent>)> = Vec::new();
+
+    fn replay_processed(events: &mut event::Events<ConfigEvent>, elapsed: Duration, sink: &str, processed: Vec<ProcessedInputEvent<ConfigEvent>>, actual: &mut HashMap<String, Vec<InputEvent>>, holds: &mut Vec<(Duration, String, HoldTimer<ConfigEvent>)>) {
+        for processed in processed {
+            match processed {
+                ProcessedInputEvent::InputEvent(event) => {
+                    println!("{}", RecordedEvent {
+                        elapsed: elapsed,
+                        sink: sink.into(),
+                        direction: Direction::Out,
+                        event: event,
+                    });
+                    actual.entry(sink.into()).or_insert(Default::default()).push(event);
+                },
+                ProcessedInputEvent::HotkeyEvent(hotkey) => {
+                    println!("# hotkey {:?}", hotkey);
+                    replay_hotkey(events, &hotkey);
+                },
+                ProcessedInputEvent::HoldTimer(hold) => holds.push((elapsed + hold.delay(), sink.into(), hold)),
+            }
+        }
+    }
+
+    for e in recording {
+        let (expired, pending): (Vec<_>, _) = holds.into_iter()
+            .partition(|&(deadline, _, _)| deadline <= e.elapsed);
+        holds = pending;
+        for (deadline, sink, hold) in expired {
+            let processed = events.process_hold(hold);
+            replay_processed(&mut events, deadline, &sink, processed, &mut actual, &mut holds);
+        }
+
+        match e.direction {
+            Direction::Out => expected.entry(e.sink).or_insert(Default::default()).push(e.event),
+            Direction::In => {
+                let processed = events.process_input_event(e.event, sink_time_source(&e.sink));
+                replay_processed(&mut events, e.elapsed, &e.sink, processed, &mut actual, &mut holds);
+            },
+        }
+    }
+
+    // keys still held when the recording ended
+    for (deadline, sink, hold) in mem::replace(&mut holds, Vec::new()) {
+        let processed = events.process_hold(hold);
+        replay_processed(&mut events, deadline, &sink, processed, &mut actual, &mut holds);
+    }
+
+    let sinks: HashSet<_> = expected.keys().chain(actual.keys()).cloned().collect();
+    let mut mismatches = 0;
+    for sink in sinks {
+        let expected = expected.get(&sink).map(|e| &e[..]).unwrap_or(&[]);
+        let actual = actual.get(&sink).map(|e| &e[..]).unwrap_or(&[]);
+        // timestamps of synthesized events aren't meaningful
+        let mismatch = (0..expected.len().max(actual.len())).find(|&i| match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) => e.kind != a.kind || e.code != a.code || e.value != a.value,
+            _ => true,
+        });
+        if let Some(i) = mismatch {
+            mismatches += 1;
+            let _ = writeln!(io::stderr(), "{}: output differs from the recording at event {}: expected {:?}, got {:?}",
+                sink, i, expected.get(i), actual.get(i)
+            );
+        }
+    }
+
+    Ok(if mismatches == 0 { 0 } else { 1 })
+}
+
+// only layers are replayed, as the rest either needs a running screenstub or
+// doesn't change how input is processed
+fn replay_hotkey(events: &mut event::Events<ConfigEvent>, hotkey: &ConfigEvent) {
+    match *hotkey {
+        ConfigEvent::EnableLayer(ref layer) => {
+            events.enable_layer(layer);
+        },
+        ConfigEvent::DisableLayer(ref layer) => {
+            events.disable_layer(layer);
+        },
+        ConfigEvent::ToggleLayer(ref layer) => {
+            events.toggle_layer(layer);
+        },
+        _ => (),
+    }
+}
+
+fn replay_uinput(recording: Vec<RecordedEvent>) -> Result<i32, Error> {
+    let uinput_id = InputId {
+        bustype: input::sys::BUS_VIRTUAL,
+        vendor: 0x16c0,
+        product: 0x05df,
+        version: 1,
+    };
+    let mut uinput_abs = uinput::Builder::new()
+        .name("screenstub-replay-abs")
+        .id(&uinput_id)
+        .x_config_abs().create()?;
+    info!("uinput abs path: {}", uinput_abs.path().display());
+
+    let mut uinput_rel = uinput::Builder::new()
+        .name("screenstub-replay-rel")
+        .id(&uinput_id)
+        .x_config_rel().create()?;
+    info!("uinput rel path: {}", uinput_rel.path().display());
+
+    // give whatever is watching the devices a chance to open them
+    sleep(Duration::from_secs(2));
+
+    let start = Instant::now();
+    for e in recording.into_iter().filter(|e| e.direction == Direction::Out) {
+        let elapsed = start.elapsed();
+        if e.elapsed > elapsed {
+            sleep(e.elapsed - elapsed);
+        }
+
+        let uinput = match &e.sink[..] {
+            "abs" => &mut uinput_abs,
+            "rel" => &mut uinput_rel,
+            sink => {
+                warn!("unknown recorded sink {}", sink);
+                continue
+            },
+        };
+        uinput.write_event(&e.event)?;
+    }
+
+    Ok(0)
+}
+
 fn convert_user_event(event: UserEvent) -> Rc<ConfigEvent> {
     Rc::new(match event {
         UserEvent::ShowGuest => ConfigEvent::ShowGuest,
@@ -373,13 +792,117 @@ fn convert_user_event(event: UserEvent) -> Rc<ConfigEvent> {
     })
 }
 
-fn convert_hotkey(hotkey: config::ConfigHotkey) -> (Hotkey<ConfigEvent>, bool) {
+fn convert_hotkey(hotkey: config::ConfigHotkey) -> (Hotkey<ConfigEvent>, bool, bool) {
     (
-        Hotkey::new(hotkey.triggers, hotkey.modifiers, hotkey.events),
+        Hotkey::new(hotkey.triggers, hotkey.modifiers, hotkey.events)
+            .keysyms(hotkey.keysyms.iter().filter_map(|name| {
+                let keysym = event::keysym::from_name(name);
+                if keysym.is_none() {
+                    warn!("unknown hotkey keysym {}", name);
+                }
+                keysym
+            }))
+            .wheel(hotkey.wheel.into_iter().map(convert_wheel))
+            .sequence(hotkey.sequence.into_iter().map(convert_hotkey_step), Duration::from_millis(hotkey.timeout_ms))
+            .mode(convert_hotkey_mode(hotkey.mode), Duration::from_millis(hotkey.threshold_ms))
+            .consume(hotkey.consume),
         !hotkey.on_release,
+        hotkey.global,
+    )
+}
+
+fn describe_hotkey(hotkey: &config::ConfigHotkey) -> String {
+    let keys: Vec<_> = hotkey.modifiers.iter().chain(&hotkey.triggers).map(|k| format!("{:?}", k))
+        .chain(hotkey.keysyms.iter().cloned())
+        .chain(hotkey.wheel.iter().map(|w| format!("wheel {:?}", w).to_lowercase()))
+        .collect();
+    let events: Vec<_> = hotkey.events.iter().map(|e| match serde_json::to_value(e) {
+        Ok(serde_json::Value::String(e)) => e,
+        Ok(e) => e.to_string(),
+        Err(..) => format!("{:?}", e),
+    }).collect();
+
+    format!("  {}{}: {}",
+        keys.join("+"),
+        if hotkey.sequence.is_empty() { "" } else { " ..." },
+        events.join(", "),
     )
 }
 
+fn convert_selections(selections: &[config::ConfigSelection]) -> Vec<XSelection> {
+    selections.iter().map(|&s| match s {
+        config::ConfigSelection::Clipboard => XSelection::Clipboard,
+        config::ConfigSelection::Primary => XSelection::Primary,
+    }).collect()
+}
+
+fn convert_remap(remap: config::ConfigRemap) -> Result<Remap, Error> {
+    Ok(match remap {
+        config::ConfigRemap::Chord(chord) => Remap::Chord(chord.keys()),
+        config::ConfigRemap::Sequence { sequence } => Remap::Sequence(sequence.iter().map(|c| c.keys()).collect()),
+        // the guest's layout isn't known, so text is only typed as it would be on a US one
+        config::ConfigRemap::Text { text } => Remap::Sequence(text.chars().map(|c| match event::keysym::us_char(c) {
+            Some((key, true)) => Ok(vec![Key::KeyLeftShift, key]),
+            Some((key, false)) => Ok(vec![key]),
+            None => Err(format_err!("remap text {:?} contains {:?} which can't be typed on a US layout", text, c)),
+        }).collect::<Result<_, _>>()?),
+    })
+}
+
+fn convert_remaps(remap: &HashMap<Key, config::ConfigRemap>) -> Result<Vec<(Key, Remap)>, Error> {
+    remap.iter().map(|(&from, to)| convert_remap(to.clone()).map(|to| (from, to))).collect()
+}
+
+fn convert_wheel(wheel: config::ConfigWheel) -> Wheel {
+    match wheel {
+        config::ConfigWheel::Up => Wheel::Up,
+        config::ConfigWheel::Down => Wheel::Down,
+        config::ConfigWheel::Left => Wheel::Left,
+        config::ConfigWheel::Right => Wheel::Right,
+    }
+}
+
+fn convert_hotkey_mode(mode: config::ConfigHotkeyMode) -> HotkeyMode {
+    match mode {
+        config::ConfigHotkeyMode::Normal => HotkeyMode::Normal,
+        config::ConfigHotkeyMode::Tap => HotkeyMode::Tap,
+        config::ConfigHotkeyMode::Hold => HotkeyMode::Hold,
+        config::ConfigHotkeyMode::DoubleTap => HotkeyMode::DoubleTap,
+    }
+}
+
+fn convert_hotkey_step(step: config::ConfigHotkeyStep) -> HotkeyStep {
+    match step {
+        config::ConfigHotkeyStep::Key(key) => HotkeyStep::new(Some(key), None),
+        config::ConfigHotkeyStep::Chord { triggers, modifiers } => HotkeyStep::new(triggers, modifiers),
+    }
+}
+
+// X can only grab a single keyboard key for global hotkeys
+fn check_hotkeys(hotkeys: &[config::ConfigHotkey]) -> Result<(), Error> {
+    for hotkey in hotkeys.iter().filter(|h| h.global) {
+        let trigger = hotkey.triggers.iter()
+            .find(|&&k| ConfigInputEvent::button_match(k) || k as u16 > x::MAX_KEYCODE);
+        if !hotkey.wheel.is_empty() {
+            return Err(format_err!("global hotkey {:?} + {:?} can't be triggered by the mouse wheel", hotkey.modifiers, hotkey.wheel))
+        } else if let Some(trigger) = trigger {
+            return Err(format_err!("global hotkey {:?} + {:?} can't be triggered by {:?}, only by keyboard keys", hotkey.modifiers, hotkey.triggers, trigger))
+        }
+    }
+
+    Ok(())
+}
+
+fn add_hotkeys(events: &mut event::Events<ConfigEvent>, hotkeys: Vec<config::ConfigHotkey>) {
+    hotkeys.into_iter()
+        .map(convert_hotkey)
+        .for_each(|(hotkey, on_press, global)| if global {
+            events.add_global_hotkey(hotkey, on_press)
+        } else {
+            events.add_hotkey(hotkey, on_press)
+        });
+}
+
 fn convert_display(monitor: config::ConfigMonitor) -> SearchDisplay {
     SearchDisplay {
         manufacturer_id: monitor.manufacturer,
@@ -415,57 +938,144 @@ impl<F: IntoFuture<Item=(), Error=Error> + 'static> From<F> for ProcessedUserEve
     }
 }
 
+// what templates need from the DDC monitor, kept so they don't have to wait on the DDC thread
+#[cfg(feature = "with-ddcutil")]
+#[derive(Debug, Clone, Default)]
+struct DdcTemplateInfo {
+    monitor: Option<String>,
+    inputs: HashMap<u8, String>,
+    bus: Option<u8>,
+}
+
+#[cfg(feature = "with-ddcutil")]
+impl DdcTemplateInfo {
+    fn new(ddc: &Monitor) -> Self {
+        DdcTemplateInfo {
+            monitor: ddc.info().map(|info| info.model_name().to_owned()),
+            inputs: ddc.inputs().cloned().unwrap_or_default(),
+            bus: ddc.i2c_bus(),
+        }
+    }
+}
+
 pub struct UserProcess {
     grabs: Rc<RefCell<HashMap<ConfigGrabMode, Grab>>>,
+    // the layers each grab enabled once it succeeded
+    grab_layers: Rc<RefCell<HashMap<ConfigGrabMode, Vec<String>>>>,
     handle: Handle,
     ddc_pool: CpuPool,
     showing_guest: Rc<Cell<bool>>,
     input_guest: Arc<SearchInput>,
     input_host: Arc<SearchInput>,
     input_host_value: Arc<AtomicUsize>,
+    input_guest_value: Arc<AtomicUsize>,
     ddc_host: ConfigDdcHost,
     ddc_guest: ConfigDdcGuest,
+    monitor_name: Option<String>,
     #[cfg(feature = "with-ddcutil")]
     ddc: Arc<Mutex<Monitor>>,
+    #[cfg(feature = "with-ddcutil")]
+    ddc_info: Arc<Mutex<DdcTemplateInfo>>,
     qemu: Rc<RefCell<Qemu>>,
-    input_organic_sender: un_mpsc::Sender<InputEvent>,
     input_rel_sender: un_mpsc::Sender<InputEvent>,
     x_input_filter: Rc<RefCell<InputEventFilter>>,
     timer: Rc<Timer>,
+    events: Rc<RefCell<event::Events<ConfigEvent>>>,
+    screen: ConfigScreen,
+    profile: Option<String>,
+    uinput_devices: Vec<(&'static str, PathBuf)>,
+    xreq_sender: mpsc::Sender<XRequest>,
+    last_error: Option<String>,
+    latency: Vec<(&'static str, Rc<uinput::Latency>)>,
 }
 
 impl UserProcess {
-    fn new(handle: Handle, ddc_pool: CpuPool, display: SearchDisplay, input_host: SearchInput, input_guest: SearchInput, ddc: ConfigDdc, qemu: Rc<RefCell<Qemu>>, input_organic_sender: un_mpsc::Sender<InputEvent>, input_rel_sender: un_mpsc::Sender<InputEvent>, x_input_filter: Rc<RefCell<InputEventFilter>>, timer: Rc<Timer>) -> Self {
+    fn new(handle: Handle, ddc_pool: CpuPool, display: SearchDisplay, input_host: SearchInput, input_guest: SearchInput, ddc: ConfigDdc, qemu: Rc<RefCell<Qemu>>, input_rel_sender: un_mpsc::Sender<InputEvent>, x_input_filter: Rc<RefCell<InputEventFilter>>, timer: Rc<Timer>, events: Rc<RefCell<event::Events<ConfigEvent>>>, screen: ConfigScreen, profile: Option<String>, uinput_devices: Vec<(&'static str, PathBuf)>, xreq_sender: mpsc::Sender<XRequest>) -> Self {
         UserProcess {
             grabs: Default::default(),
+            grab_layers: Default::default(),
             handle: handle,
             showing_guest: Rc::new(Cell::new(false)),
             input_guest: Arc::new(input_guest),
             input_host: Arc::new(input_host),
             input_host_value: Arc::new(AtomicUsize::new(0x100)),
+            input_guest_value: Arc::new(AtomicUsize::new(0x100)),
             ddc_host: ddc.host,
             ddc_guest: ddc.guest,
+            monitor_name: display.model_name.clone().or_else(|| display.manufacturer_id.clone()),
             #[cfg(feature = "with-ddcutil")]
             ddc: Arc::new(Mutex::new(Monitor::new(display))),
+            #[cfg(feature = "with-ddcutil")]
+            ddc_info: Default::default(),
             ddc_pool: ddc_pool,
             qemu: qemu,
-            input_organic_sender: input_organic_sender,
             input_rel_sender: input_rel_sender,
             x_input_filter: x_input_filter,
             timer: timer,
+            events: events,
+            screen: screen,
+            profile: profile,
+            uinput_devices: uinput_devices,
+            xreq_sender: xreq_sender,
+            last_error: None,
+            latency: Default::default(),
+        }
+    }
+
+    // layers that were already enabled are left alone, and stay enabled after the grab ends
+    fn enable_grab_layers(events: &RefCell<event::Events<ConfigEvent>>, grab_layers: &RefCell<HashMap<ConfigGrabMode, Vec<String>>>, mode: ConfigGrabMode, layers: &[String]) {
+        let mut events = events.borrow_mut();
+        let enabled = layers.iter()
+            .filter(|layer| !events.layer_enabled(layer) && events.enable_layer(layer))
+            .cloned().collect();
+        grab_layers.borrow_mut().insert(mode, enabled);
+    }
+
+    fn disable_grab_layers(&self, mode: ConfigGrabMode) {
+        let mut events = self.events.borrow_mut();
+        for layer in self.grab_layers.borrow_mut().remove(&mode).unwrap_or_default() {
+            events.disable_layer(&layer);
+        }
+    }
+
+    // X grabs are only known to have succeeded once the X state catches up
+    pub fn x_state(&mut self, state: &XState) {
+        let devices_grabbed = self.grabs.borrow().contains_key(&ConfigGrabMode::XDevice);
+        if state.devices_grabbed && !devices_grabbed {
+            self.grabs.borrow_mut().insert(ConfigGrabMode::XDevice, Grab::XDevice);
+        } else if !state.devices_grabbed && devices_grabbed {
+            self.grabs.borrow_mut().remove(&ConfigGrabMode::XDevice);
+            self.disable_grab_layers(ConfigGrabMode::XDevice);
+        }
+
+        let modes = [
+            (ConfigGrabMode::XCore, state.grabbed),
+            (ConfigGrabMode::XLock, state.grabbed),
+            (ConfigGrabMode::XDevice, state.devices_grabbed),
+        ];
+        for &(mode, grabbed) in &modes {
+            if grabbed && self.grabs.borrow().contains_key(&mode) && !self.grab_layers.borrow().contains_key(&mode) {
+                let layers = self.screen.grab_layers.get(&mode).cloned().unwrap_or_default();
+                Self::enable_grab_layers(&self.events, &self.grab_layers, mode, &layers);
+            }
         }
     }
 
     fn grab(&mut self, grab: &ConfigGrab) -> Vec<ProcessedUserEvent> {
         let mode = grab.mode();
 
-        let res = vec![match *grab {
+        let mut res = vec![match *grab {
             ConfigGrab::XCore => {
                 self.grabs.borrow_mut().insert(mode, Grab::XCore);
-                xreq(XRequest::Grab)
+                xreq(XRequest::Grab { raw_motion: self.screen.raw_motion, pointer_lock: false })
             },
+            ConfigGrab::XLock => {
+                self.grabs.borrow_mut().insert(mode, Grab::XLock);
+                xreq(XRequest::Grab { raw_motion: self.screen.raw_motion, pointer_lock: true })
+            },
+            // only counts as grabbed once X reports the devices were, see `x_state`
+            ConfigGrab::XDevice { ref devices } => xreq(XRequest::GrabDevices(devices.clone())),
             ref grab @ ConfigGrab::Evdev { .. } => {
-                let input_organic_sender = self.input_organic_sender.clone();
                 let input_rel_sender = self.input_rel_sender.clone();
                 let grab = grab.clone();
                 let grabs = self.grabs.clone();
@@ -473,13 +1083,17 @@ impl UserProcess {
                 let x_filter = self.x_input_filter.clone();
                 let qemu = self.qemu.clone();
                 let timer = self.timer.clone();
-                future::lazy(move || GrabEvdev::new(&grab, &handle, &input_organic_sender, &input_rel_sender).map(|g| (grab, g)))
+                let events = self.events.clone();
+                let grab_layers = self.grab_layers.clone();
+                let layers = self.screen.grab_layers.get(&mode).cloned().unwrap_or_default();
+                future::lazy(move || GrabEvdev::new(&grab, &handle, &input_rel_sender).map(|g| (grab, g)))
                     .map(move |(grabconf, grab)| {
                         let mut grabs = grabs.borrow_mut();
                         let uinput = grab.uinput_path().and_then(|p| grab.uinput_id().map(|n| (p.to_owned(), n.to_owned())));
                         let is_mouse = grab.is_mouse;
                         grabs.insert(mode, grab.into());
                         x_filter.borrow_mut().set_filter(xcore_ignore(&grabconf).iter().cloned());
+                        Self::enable_grab_layers(&events, &grab_layers, mode, &layers);
                         (uinput, is_mouse)
                     })
                     .and_then(move |(uinput, is_mouse)| {
@@ -501,17 +1115,43 @@ impl UserProcess {
                     })
                 .into()
             },
-            _ => future::err(format_err!("grab {:?} unimplemented", mode)).into(),
         }];
 
+        // relative motion from the window needs the guest to see a mouse rather than a tablet
+        if self.x_relative(mode) {
+            res.push(self.qemu.borrow_mut().set_is_mouse(true).into());
+        }
+
         res
     }
 
+    fn x_relative(&self, mode: ConfigGrabMode) -> bool {
+        match mode {
+            ConfigGrabMode::XCore => self.screen.raw_motion,
+            ConfigGrabMode::XLock => true,
+            _ => false,
+        }
+    }
+
     fn ungrab(&mut self, grab: ConfigGrabMode) -> Vec<ProcessedUserEvent> {
-        let res = vec![match grab {
-            ConfigGrabMode::XCore => {
+        self.disable_grab_layers(grab);
+        let mut res = vec![match grab {
+            ConfigGrabMode::XCore | ConfigGrabMode::XLock => {
+                let mut grabs = self.grabs.borrow_mut();
+                grabs.remove(&grab);
+                // both share the one X grab, so whichever remains takes it back over
+                let raw_motion = self.screen.raw_motion;
+                if grabs.contains_key(&ConfigGrabMode::XLock) {
+                    xreq(XRequest::Grab { raw_motion: raw_motion, pointer_lock: true })
+                } else if grabs.contains_key(&ConfigGrabMode::XCore) {
+                    xreq(XRequest::Grab { raw_motion: raw_motion, pointer_lock: false })
+                } else {
+                    xreq(XRequest::Ungrab)
+                }
+            },
+            ConfigGrabMode::XDevice => {
                 self.grabs.borrow_mut().remove(&grab);
-                xreq(XRequest::Ungrab)
+                xreq(XRequest::UngrabDevices)
             },
             ConfigGrabMode::Evdev => {
                 if let Some(Grab::Evdev(grab)) = self.grabs.borrow_mut().remove(&grab) {
@@ -529,27 +1169,158 @@ impl UserProcess {
                     future::ok(()).into()
                 }
             },
-            grab => future::err(format_err!("ungrab {:?} unimplemented", grab)).into(),
         }];
 
+        let relative = self.grabs.borrow().keys().any(|&mode| self.x_relative(mode));
+        if self.x_relative(grab) && !relative {
+            res.push(self.qemu.borrow_mut().set_is_mouse(false).into());
+        }
+
         res
     }
 
-    fn map_input_arg<S: AsRef<OsStr>>(&self, s: &S, input: Option<u8>) -> OsString {
-        let s = s.as_ref();
-        if let Some(input) = input {
-            let bytes = s.as_bytes();
-            if bytes == b"{}" {
-                OsString::from(format!("{}", input))
-            } else if bytes == b"{:x}" {
-                OsString::from(format!("{:02x}", input))
-            } else if bytes == b"0x{:x}" {
-                OsString::from(format!("0x{:02x}", input))
-            } else {
-                s.to_owned()
-            }
+    fn switch_profile(&mut self, name: &str) -> Vec<ProcessedUserEvent> {
+        if self.profile.as_ref().map(|p| &p[..]) == Some(name) {
+            return Default::default()
+        }
+
+        let config = match self.screen.with_profile(name) {
+            Some(config) => config,
+            None => return vec![future::err(format_err!("profile {} not found", name)).into()],
+        };
+        let remap = match check_hotkeys(&config.hotkeys).and_then(|()| convert_remaps(&config.key_remap)) {
+            Ok(remap) => remap,
+            Err(e) => return vec![future::err(e).into()],
+        };
+
+        let mut res = Vec::new();
+
+        // grabs started by hotkeys are released too, they all belong to the old VM
+        let grabs: Vec<_> = self.grabs.borrow().keys().cloned().collect();
+        for grab in grabs {
+            res.extend(self.ungrab(grab));
+        }
+
+        // the removals are sent to the old VM before it's replaced
+        let remove = future::join_all(self.uinput_devices.iter()
+            .map(|&(id, _)| self.qemu.borrow_mut().remove_evdev(id).then(move |res| {
+                if let Err(ref e) = res {
+                    warn!("Failed to remove {} from the previous VM {} {:?}", id, e, e);
+                }
+                Ok::<_, Error>(())
+            })).collect::<Vec<_>>()
+        );
+        let old_qemu = mem::replace(&mut *self.qemu.borrow_mut(), Qemu::new(config.qemu, self.handle.clone()));
+        let qemu = self.qemu.clone();
+        let devices = self.uinput_devices.clone();
+        res.push(remove.and_then(move |_| {
+            // only let go of the old VM once its devices are gone
+            drop(old_qemu);
+
+            let add = {
+                let mut qemu = qemu.borrow_mut();
+                future::join_all(devices.into_iter()
+                    .map(|(id, path)| qemu.add_evdev(id, path))
+                    .collect::<Vec<_>>()
+                )
+            };
+            add.and_then(move |_| {
+                let mut qemu = qemu.borrow_mut();
+                qemu.set_is_mouse(false)
+            })
+        }).into());
+
+        self.handle.spawn(self.disconnect_guest().map_err(|e| debug!("ssh disconnect failed {}", e)));
+        self.input_guest = Arc::new(convert_input(config.guest_source));
+        self.ddc_guest = config.ddc.guest;
+
+        {
+            let mut events = self.events.borrow_mut();
+            events.clear_hotkeys();
+            events.clear_remap();
+            add_hotkeys(&mut events, config.hotkeys);
+            remap.into_iter().for_each(|(from, to)| events.add_remap(from, to));
+            res.push(xreq(XRequest::GrabHotkeys(events.x_hotkeys())));
+        }
+
+        info!("Switched to profile {}", name);
+        self.profile = Some(name.to_owned());
+
+        let grabs = self.screen.profiles.get(name).map(|p| p.grabs.clone()).unwrap_or_default();
+        for grab in grabs {
+            res.extend(self.grab(&grab));
+        }
+
+        if self.showing_guest.get() {
+            res.extend(self.show_guest());
+        }
+
+        res
+    }
+
+    fn host_value(&self) -> Option<u8> {
+        let input_host_value = self.input_host_value.load(Ordering::Relaxed);
+        self.input_host.value.or_else(|| if input_host_value < 0x100 { Some(input_host_value as u8) } else { None })
+    }
+
+    fn guest_value(&self) -> Option<u8> {
+        let input_guest_value = self.input_guest_value.load(Ordering::Relaxed);
+        self.input_guest.value.or_else(|| if input_guest_value < 0x100 { Some(input_guest_value as u8) } else { None })
+    }
+
+    #[cfg(feature = "with-ddcutil")]
+    fn ddc_template_info(&self, value: Option<u8>) -> (Option<String>, Option<String>, Option<u8>) {
+        match self.ddc_info.lock() {
+            Ok(info) => (
+                info.monitor.clone(),
+                value.and_then(|v| info.inputs.get(&v).cloned()),
+                info.bus,
+            ),
+            Err(..) => (None, None, None),
+        }
+    }
+
+    #[cfg(not(feature = "with-ddcutil"))]
+    fn ddc_template_info(&self, _value: Option<u8>) -> (Option<String>, Option<String>, Option<u8>) {
+        (None, None, None)
+    }
+
+    fn template_vars(&self, value: Option<u8>, input: Option<&SearchInput>) -> TemplateVars {
+        let (monitor, input_name, bus) = self.ddc_template_info(value);
+        let mut vars = TemplateVars::new();
+
+        vars.number("value", value)
+            .string("monitor", monitor.or_else(|| self.monitor_name.clone()))
+            .string("input", input.and_then(|i| i.name.clone()).or(input_name))
+            .number("host", self.host_value())
+            .number("guest", self.guest_value())
+            .number("bus", bus)
+            .string("profile", self.profile.clone());
+
+        vars
+    }
+
+    fn ssh_guest(&self) -> Option<Ssh> {
+        match self.ddc_guest {
+            ConfigDdcGuest::Ssh { ref host, ref user, port, ref identity, connect_timeout, ref options, ref program, .. } =>
+                Some(Ssh::new(
+                    program.as_ref().map(|p| &p[..]),
+                    host,
+                    user.as_ref().map(|u| &u[..]),
+                    port,
+                    identity.as_ref().map(|i| &i[..]),
+                    connect_timeout,
+                    options,
+                )),
+            _ => None,
+        }
+    }
+
+    fn disconnect_guest(&self) -> Box<Future<Item=(), Error=Error>> {
+        if let Some(ssh) = self.ssh_guest() {
+            ssh.disconnect(&self.handle)
         } else {
-            s.to_owned()
+            Box::new(future::ok(())) as Box<_>
         }
     }
 
@@ -559,6 +1330,15 @@ impl UserProcess {
                 Box::new(future::ok(())) as Box<_>,
             ConfigDdcGuest::GuestExec(..) =>
                 Box::new(self.qemu.borrow_mut().guest_info()) as Box<_>,
+            ConfigDdcGuest::Ssh { .. } => Box::new(
+                // establishes the master connection before the guest takes over the screen,
+                // switching shouldn't depend on it since the guest may still be starting up
+                self.ssh_guest().unwrap().connect(&self.handle)
+                    .or_else(|e| {
+                        warn!("Failed to connect to the guest over ssh {} {:?}", e, e);
+                        Ok(())
+                    })
+            ) as Box<_>,
         }
     }
 
@@ -569,13 +1349,18 @@ impl UserProcess {
             #[cfg(feature = "with-ddcutil")]
             ConfigDdcHost::Libddcutil => {
                 let ddc = self.ddc.clone();
+                let ddc_info = self.ddc_info.clone();
                 let input = self.input_guest.clone();
                 let input_host = self.input_host.clone();
                 let input_host_value = self.input_host_value.clone();
+                let input_guest_value = self.input_guest_value.clone();
                 let ddc_pool = self.ddc_pool.clone();
                 vec![self.detect_guest().and_then(move |_| futures::sync::oneshot::spawn_fn(move || {
                     let mut ddc = ddc.lock().map_err(|e| format_err!("DDC mutex poisoned {:?}", e))?;
                     ddc.to_display()?;
+                    if let Ok(mut ddc_info) = ddc_info.lock() {
+                        *ddc_info = DdcTemplateInfo::new(&ddc);
+                    }
                     if let Some(input) = ddc.our_input() {
                         if input_host.name.is_some() {
                             if let Some(input) = ddc.match_input(&input_host) {
@@ -586,6 +1371,7 @@ impl UserProcess {
                         }
                     }
                     if let Some(input) = ddc.match_input(&input) {
+                        input_guest_value.store(input as _, Ordering::Relaxed);
                         ddc.set_input(input)
                     } else {
                         Err(format_err!("DDC guest input source not found"))
@@ -598,8 +1384,11 @@ impl UserProcess {
                 vec![future::err(format_err!("ddcutil unimplemented")).into()]
             },
             ConfigDdcHost::Exec(ref args) => {
-                let input = self.input_guest.value;
-                vec![exec(&self.handle, args.into_iter().map(|i| self.map_input_arg(i, input)))
+                let args = match self.template_vars(self.guest_value(), Some(&self.input_guest)).render_all(args) {
+                    Ok(args) => args,
+                    Err(e) => return vec![future::err(e).into()],
+                };
+                vec![exec(&self.handle, args)
                     .inspect(move |&()| showing_guest.set(true))
                     .into()
                 ]
@@ -608,8 +1397,7 @@ impl UserProcess {
     }
 
     fn show_host(&mut self) -> Box<Future<Item=(), Error=Error>> {
-        let input_host_value = self.input_host_value.load(Ordering::Relaxed);
-        let input = self.input_host.value.or_else(|| if input_host_value < 0x100 { Some(input_host_value as u8) } else { None });
+        let vars = self.template_vars(self.host_value(), Some(&self.input_host));
         let showing_guest = self.showing_guest.clone();
 
         match self.ddc_guest {
@@ -618,32 +1406,156 @@ impl UserProcess {
                 Box::new(future::ok(())) as Box<_>
             }, // TODO: not really sure why this is an option
             ConfigDdcGuest::Exec(ref args) => {
-                let input = self.input_guest.value;
-                Box::new(exec(&self.handle, args.into_iter().map(|i| self.map_input_arg(i, input)))
+                let args = match vars.render_all(args) {
+                    Ok(args) => args,
+                    Err(e) => return Box::new(future::err(e)) as Box<_>,
+                };
+                Box::new(exec(&self.handle, args)
                     .inspect(move |&()| showing_guest.set(false))
                 ) as Box<_>
             },
             ConfigDdcGuest::GuestExec(ref args) => {
+                let args = match vars.render_all(args) {
+                    Ok(args) => args,
+                    Err(e) => return Box::new(future::err(e)) as Box<_>,
+                };
+                Box::new(
+                    self.qemu.borrow_mut().guest_exec(args)
+                    .inspect(move |&()| showing_guest.set(false))
+                ) as Box<_>
+            },
+            ConfigDdcGuest::Ssh { ref command, .. } => {
+                let args = match vars.render_all(command) {
+                    Ok(args) => args,
+                    Err(e) => return Box::new(future::err(e)) as Box<_>,
+                };
                 Box::new(
-                    self.qemu.borrow_mut().guest_exec(args.into_iter().map(|i| self.map_input_arg(i, input)))
+                    self.ssh_guest().unwrap().exec(&self.handle, args)
                     .inspect(move |&()| showing_guest.set(false))
                 ) as Box<_>
             },
         }
     }
 
+    // reads the host selection, which is then passed to set_guest_clipboard
+    fn clipboard_to_guest(&self) -> Vec<ProcessedUserEvent> {
+        match self.screen.clipboard {
+            Some(ref clipboard) => vec![xreq(XRequest::GetSelection(convert_selections(&clipboard.selections)))],
+            None => Default::default(),
+        }
+    }
+
+    fn set_guest_clipboard(&mut self, text: String) -> Box<Future<Item=(), Error=Error>> {
+        match self.screen.clipboard {
+            Some(ref clipboard) => Box::new(
+                self.qemu.borrow_mut().guest_exec_output(clipboard.write.clone(), Some(text.into_bytes()))
+                .map(drop)
+            ) as Box<_>,
+            None => Box::new(future::ok(())) as Box<_>,
+        }
+    }
+
+    fn clipboard_to_host(&mut self) -> Vec<ProcessedUserEvent> {
+        match self.screen.clipboard {
+            Some(ref clipboard) => {
+                let selections = convert_selections(&clipboard.selections);
+                let xreq_sender = self.xreq_sender.clone();
+                user(self.qemu.borrow_mut().guest_exec_output(clipboard.read.clone(), None)
+                    .and_then(move |output| {
+                        let output = String::from_utf8_lossy(&output);
+                        // the helper ends its output with a line break of its own
+                        let text = if output.ends_with("\r\n") {
+                            &output[..output.len() - 2]
+                        } else if output.ends_with('\n') {
+                            &output[..output.len() - 1]
+                        } else {
+                            &output[..]
+                        }.to_owned();
+                        if text.is_empty() {
+                            Either::A(future::ok(()))
+                        } else {
+                            Either::B(xreq_sender.send(XRequest::SetSelection(selections, text))
+                                .map(drop).map_err(|e| format_err!("{}", e))
+                            )
+                        }
+                    })
+                )
+            },
+            None => Default::default(),
+        }
+    }
+
+    fn exec_env(&self) -> Vec<(&'static str, String)> {
+        let mut grabs: Vec<_> = self.grabs.borrow().keys().map(|g| g.name()).collect();
+        grabs.sort();
+
+        vec![
+            ("SCREENSTUB_SHOWING_GUEST", if self.showing_guest.get() { "1" } else { "0" }.into()),
+            ("SCREENSTUB_GRABBED", if grabs.is_empty() { "0" } else { "1" }.into()),
+            ("SCREENSTUB_GRABS", grabs.join(" ")),
+            ("SCREENSTUB_PROFILE", self.profile.clone().unwrap_or_default()),
+        ]
+    }
+
+    fn add_latency(&mut self, name: &'static str, latency: Rc<uinput::Latency>) {
+        self.latency.push((name, latency));
+    }
+
+    fn set_last_error(&mut self, e: &Error) {
+        self.last_error = Some(e.to_string());
+    }
+
+    // text shown in the screenstub window
+    fn status(&self) -> Vec<String> {
+        let mut grabs: Vec<_> = self.grabs.borrow().keys().map(|g| g.name()).collect();
+        grabs.sort();
+        let screen = self.profile.as_ref().and_then(|p| self.screen.with_profile(p));
+        let screen = screen.as_ref().unwrap_or(&self.screen);
+
+        let mut status = vec![
+            format!("Profile: {}", self.profile.as_ref().map(|p| &p[..]).unwrap_or("default")),
+            format!("Showing: {}", if self.showing_guest.get() { "guest" } else { "host" }),
+            format!("Grabs: {}", if grabs.is_empty() { "none".into() } else { grabs.join(" ") }),
+        ];
+        let latency: Vec<_> = self.latency.iter()
+            .filter(|&&(_, ref latency)| latency.frames() > 0)
+            .map(|&(name, ref latency)| format!("{} {:.1}ms avg {:.1}ms max", name,
+                duration_ms(latency.average()), duration_ms(latency.max())
+            )).collect();
+        if !latency.is_empty() {
+            status.push(format!("Latency: {}", latency.join(", ")));
+        }
+        if let Some(ref e) = self.last_error {
+            status.push(format!("Last error: {}", e));
+        }
+        status.push(String::new());
+        status.push("Hotkeys:".into());
+        status.extend(screen.hotkeys.iter().map(describe_hotkey));
+
+        status
+    }
+
     fn process_user_event(&mut self, event: &ConfigEvent) -> Vec<ProcessedUserEvent> {
         trace!("process_user_event({:?})", event);
         info!("User event {:?}", event);
         match *event {
             ConfigEvent::ShowHost => {
-                user(self.show_host())
+                // queued on the guest agent ahead of a guest_exec handback
+                let mut res = self.clipboard_to_host();
+                res.extend(user(self.show_host()));
+                res
             },
             ConfigEvent::ShowGuest => {
-                self.show_guest()
+                let mut res = self.clipboard_to_guest();
+                res.extend(self.show_guest());
+                res
             },
-            ConfigEvent::Exec(ref args) => {
-                vec![exec(&self.handle, args).into()]
+            ConfigEvent::Exec(ref exec) => {
+                // there's no input value here, so "{}" is left alone for the command
+                match self.template_vars(None, None).remove("value").render_exec(exec) {
+                    Ok(command) => vec![exec_command(&self.handle, &self.timer, command, self.exec_env()).into()],
+                    Err(e) => vec![future::err(e).into()],
+                }
             }
             ConfigEvent::ToggleGrab(ref grab) => {
                 let mode = grab.mode();
@@ -655,9 +1567,14 @@ impl UserProcess {
             }
             ConfigEvent::ToggleShow => {
                 if self.showing_guest.get() {
-                    user(self.show_host())
+                    // queued on the guest agent ahead of a guest_exec handback
+                    let mut res = self.clipboard_to_host();
+                    res.extend(user(self.show_host()));
+                    res
                 } else {
-                    self.show_guest()
+                    let mut res = self.clipboard_to_guest();
+                    res.extend(self.show_guest());
+                    res
                 }
             },
             ConfigEvent::Grab(ref grab) => self.grab(grab),
@@ -668,6 +1585,22 @@ impl UserProcess {
             ConfigEvent::UnstickHost => {
                 vec![xreq(XRequest::UnstickHost)]
             }
+            ConfigEvent::SwitchProfile(ref profile) => {
+                self.switch_profile(profile)
+            },
+            ConfigEvent::EnableLayer(ref layer) => {
+                self.events.borrow_mut().enable_layer(layer);
+                Default::default()
+            },
+            ConfigEvent::DisableLayer(ref layer) => {
+                self.events.borrow_mut().disable_layer(layer);
+                Default::default()
+            },
+            ConfigEvent::ToggleLayer(ref layer) => {
+                let enabled = self.events.borrow_mut().toggle_layer(layer);
+                info!("Layer {} {}", layer, if enabled { "enabled" } else { "disabled" });
+                Default::default()
+            },
             ConfigEvent::Shutdown => {
                 user(self.qemu.borrow_mut().guest_shutdown(QemuShutdownMode::Shutdown))
             },
@@ -686,6 +1619,8 @@ struct Qemu {
     driver: ConfigQemuDriver,
     qmp: Option<String>,
     ga: Option<String>,
+    // the guest agent only serves one client at a time, so its requests are queued here
+    ga_pool: CpuPool,
     handle: Handle,
 }
 
@@ -696,55 +1631,42 @@ impl Qemu {
             driver: qemu.driver,
             qmp: qemu.qmp_socket,
             ga: qemu.ga_socket,
+            ga_pool: futures_cpupool::Builder::new()
+                .pool_size(1)
+                .name_prefix("QGA")
+                .create(),
             handle: handle,
         }
     }
 
     // TODO: none of these need to be mut probably?
-    pub fn guest_exec<I: IntoIterator<Item=S>, S: AsRef<OsStr>>(&mut self, args: I) -> Box<Future<Item=(), Error=Error>> {
-        match self.comm {
-            ConfigQemuComm::None => {
-                Box::new(future::ok(())) as Box<_>
-            },
-            ConfigQemuComm::Qemucomm => {
-                if let Some(ga) = self.ga.as_ref() {
-                    exec(&self.handle,
-                         ["qemucomm", "-g", &ga, "exec", "-w"]
-                            .iter().map(|&s| s.to_owned())
-                        .chain(args.into_iter().map(|s| s.as_ref().to_string_lossy().into_owned()))
-                    )
-                } else {
-                    Box::new(future::err(format_err!("QEMU Guest Agent socket not provided"))) as Box<_>
-                }
-            },
-            ConfigQemuComm::QMP => {
-                unimplemented!()
-            },
-            ConfigQemuComm::Console => {
-                unimplemented!()
+    fn guest_agent<T: Default + Send + 'static, F: FnOnce(&mut Qga<UnixStream>) -> Result<T, Error> + Send + 'static>(&self, f: F) -> Box<Future<Item=T, Error=Error>> {
+        match (self.comm, self.ga.clone()) {
+            (ConfigQemuComm::None, _) => Box::new(future::ok(T::default())) as Box<_>,
+            (_, Some(ga)) => Box::new(self.ga_pool.spawn_fn(move ||
+                qga_connect(&ga).and_then(|mut qga| f(&mut qga))
+            )) as Box<_>,
+            (_, None) => {
+                Box::new(future::err(format_err!("QEMU Guest Agent socket not provided"))) as Box<_>
             },
         }
     }
 
+    pub fn guest_exec<I: IntoIterator<Item=S>, S: AsRef<OsStr>>(&mut self, args: I) -> Box<Future<Item=(), Error=Error>> {
+        let args = args.into_iter().map(|s| s.as_ref().to_string_lossy().into_owned()).collect();
+        self.guest_agent(move |qga| qga_exec(qga, args, None).map(drop))
+    }
+
+    // runs a command through the guest agent and returns its output
+    pub fn guest_exec_output(&mut self, args: Vec<String>, input: Option<Vec<u8>>) -> Box<Future<Item=Vec<u8>, Error=Error>> {
+        self.guest_agent(move |qga| qga_exec(qga, args, input))
+    }
+
     pub fn guest_info(&mut self) -> Box<Future<Item=(), Error=Error>> {
-        match self.comm {
-            ConfigQemuComm::None => {
-                Box::new(future::ok(())) as Box<_>
-            },
-            ConfigQemuComm::Qemucomm => {
-                if let Some(ga) = self.ga.as_ref() {
-                    exec(&self.handle, ["qemucomm", "-g", &ga, "info"].iter().cloned())
-                } else {
-                    Box::new(future::err(format_err!("QEMU Guest Agent socket not provided"))) as Box<_>
-                }
-            },
-            ConfigQemuComm::QMP => {
-                unimplemented!()
-            },
-            ConfigQemuComm::Console => {
-                unimplemented!()
-            },
-        }
+        self.guest_agent(|qga| qga.execute(GuestInfo::default())
+            .map(|info| debug!("QEMU Guest Agent {}", info.version))
+            .map_err(From::from)
+        )
     }
 
     pub fn add_evdev<I: AsRef<OsStr>, D: AsRef<OsStr>>(&mut self, id: I, device: D) -> Box<Future<Item=(), Error=Error>> {
@@ -816,30 +1738,16 @@ impl Qemu {
     }
 
     pub fn guest_shutdown(&mut self, mode: QemuShutdownMode) -> Box<Future<Item=(), Error=Error>> {
-        match self.comm {
-            ConfigQemuComm::None => {
-                Box::new(future::ok(())) as Box<_>
-            },
-            ConfigQemuComm::Qemucomm => {
-                let mode = match mode {
-                    QemuShutdownMode::Shutdown => None,
-                    QemuShutdownMode::Reboot => Some("-r"),
-                    QemuShutdownMode::Halt => Some("-h"),
-                };
-
-                if let Some(ga) = self.ga.as_ref() {
-                    exec(&self.handle, ["qemucomm", "-g", &ga, "shutdown", mode.unwrap_or("ignore")].iter().cloned())
-                } else {
-                    Box::new(future::err(format_err!("QEMU QMP socket not provided"))) as Box<_>
-                }
-            },
-            ConfigQemuComm::QMP => {
-                unimplemented!()
-            },
-            ConfigQemuComm::Console => {
-                unimplemented!()
-            },
-        }
+        let mode = match mode {
+            QemuShutdownMode::Shutdown => "powerdown",
+            QemuShutdownMode::Reboot => "reboot",
+            QemuShutdownMode::Halt => "halt",
+        };
+
+        // there's no reply to wait for unless the shutdown fails
+        self.guest_agent(move |qga| qga.execute_nowait(&GuestShutdown {
+            mode: Some(mode.into()),
+        }).map_err(From::from))
     }
 
     pub fn remove_object<I: AsRef<OsStr>>(&mut self, id: I) -> Box<Future<Item=(), Error=Error>> {
@@ -917,6 +1825,45 @@ enum QemuShutdownMode {
     Halt,
 }
 
+const QGA_TIMEOUT_SECS: u64 = 5;
+
+fn qga_connect(socket: &str) -> Result<Qga<UnixStream>, Error> {
+    let stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(Duration::from_secs(QGA_TIMEOUT_SECS)))?;
+    let mut qga = Qga::new(stream);
+    qga.handshake()?;
+
+    Ok(qga)
+}
+
+fn qga_exec(qga: &mut Qga<UnixStream>, args: Vec<String>, input: Option<Vec<u8>>) -> Result<Vec<u8>, Error> {
+    let mut args = args.into_iter();
+    let path = args.next().ok_or_else(|| format_err!("Missing exec command"))?;
+    let exec = qga.execute(GuestExec {
+        path: path.clone(),
+        arg: args.collect(),
+        input_data: input,
+        capture_output: Some(true),
+        .. Default::default()
+    })?;
+
+    let start = Instant::now();
+    loop {
+        let status = qga.execute(GuestExecStatus::from(exec.clone()))?;
+        if status.exited {
+            break match status.exitcode {
+                Some(0) | None => Ok(status.out_data.unwrap_or_default()),
+                Some(code) => Err(format_err!("{} exited with code {}", path, code)),
+            }
+        }
+
+        if start.elapsed() > Duration::from_secs(QGA_TIMEOUT_SECS) {
+            break Err(format_err!("{} timed out", path))
+        }
+        sleep(Duration::from_millis(50));
+    }
+}
+
 fn exec<I: IntoIterator<Item=S>, S: AsRef<OsStr>>(ex: &Handle, args: I) -> Box<Future<Item=(), Error=Error>> {
     fn exit_status_error(status: ExitStatus) -> Result<(), Error> {
         if status.success() {
@@ -946,8 +1893,87 @@ fn exec<I: IntoIterator<Item=S>, S: AsRef<OsStr>>(ex: &Handle, args: I) -> Box<F
     }
 }
 
+fn exec_command(ex: &Handle, timer: &Timer, exec: ConfigExecCommand, env: Vec<(&'static str, String)>) -> Box<Future<Item=(), Error=Error>> {
+    fn exit_status_error(status: ExitStatus) -> Result<(), Error> {
+        if status.success() {
+            Ok(())
+        } else {
+            Err(if let Some(code) = status.code() {
+                format_err!("process exited with code {}", code)
+            } else {
+                format_err!("process exited with a failure")
+            })
+        }
+    }
+
+    let mut args = exec.args.into_iter();
+    let cmd = if let Some(cmd) = args.next() {
+        cmd
+    } else {
+        return Box::new(future::err(format_err!("Missing exec command"))) as Box<_>
+    };
+
+    let mut command = Command::new(&cmd);
+    command.args(args)
+        .envs(env)
+        .envs(exec.env)
+        .stdin(Stdio::null());
+    if let Some(cwd) = exec.cwd {
+        command.current_dir(cwd);
+    }
+    if exec.capture_output && !exec.detach {
+        command.stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    } else {
+        command.stdout(Stdio::null());
+    }
+
+    let child = match command.spawn_async(ex) {
+        Ok(child) => child,
+        Err(e) => return Box::new(future::err(e.into())) as Box<_>,
+    };
+
+    if exec.detach {
+        child.forget();
+        return Box::new(future::ok(())) as Box<_>
+    }
+
+    let status = if exec.capture_output {
+        let cmd = cmd.clone();
+        Box::new(child.wait_with_output().map_err(Error::from)
+            .and_then(move |output| {
+                for line in String::from_utf8_lossy(&output.stdout).lines() {
+                    info!("{}: {}", cmd, line);
+                }
+                for line in String::from_utf8_lossy(&output.stderr).lines() {
+                    warn!("{}: {}", cmd, line);
+                }
+                exit_status_error(output.status)
+            })
+        ) as Box<Future<Item=_, Error=_>>
+    } else {
+        Box::new(child.map_err(Error::from).and_then(exit_status_error)) as Box<_>
+    };
+
+    if let Some(timeout) = exec.timeout_ms {
+        // the child is killed when it is dropped on timeout
+        Box::new(status.select2(timer.sleep(Duration::from_millis(timeout)))
+            .then(move |res| match res {
+                Ok(Either::A((res, _))) => Ok(res),
+                Err(Either::A((e, _))) => Err(e),
+                Ok(Either::B(..)) => Err(format_err!("{} timed out after {}ms", cmd, timeout)),
+                Err(Either::B((e, _))) => Err(e.into()),
+            })
+        ) as Box<_>
+    } else {
+        status
+    }
+}
+
 pub enum Grab {
     XCore,
+    XLock,
+    XDevice,
     Evdev(GrabEvdev),
 }
 
@@ -965,7 +1991,7 @@ pub struct GrabEvdev {
 }
 
 impl GrabEvdev {
-    fn new(grab: &ConfigGrab, handle: &Handle, evdev_sender0: &un_mpsc::Sender<InputEvent>, evdev_sender1: &un_mpsc::Sender<InputEvent>) -> Result<Self, Error> {
+    fn new(grab: &ConfigGrab, handle: &Handle, evdev_sender: &un_mpsc::Sender<InputEvent>) -> Result<Self, Error> {
         match *grab {
             ConfigGrab::Evdev { exclusive, ref new_device_name, ref xcore_ignore, ref evdev_ignore, ref devices } => {
                 let mut uinput = if let &Some(ref name) = new_device_name {
@@ -1029,7 +2055,7 @@ impl GrabEvdev {
                         handle.spawn(dev
                             .filter(move |e| filters.filter_event(e))
                             .map_err(|_| -> un_mpsc::SendError<_> { unreachable!() })
-                            .forward(evdev_sender0.clone().fanout(evdev_sender1.clone())).map(drop).map_err(drop)
+                            .forward(evdev_sender.clone()).map(drop).map_err(drop)
                         );
                     }
                 }
@@ -1089,6 +2115,15 @@ impl InputEventFilter {
         ConfigInputEvent::from_event(e).map(|e| !self.filter.contains(&e)).unwrap_or(true)
     }
 
+    // X reports the wheel as buttons, so it's ignored along with them
+    fn filter_x_event(&self, e: &InputEvent) -> bool {
+        match EventRef::new(e) {
+            Ok(EventRef::Relative(rel)) if rel.axis == RelativeAxis::Wheel || rel.axis == RelativeAxis::HorizontalWheel =>
+                !self.filter.contains(&ConfigInputEvent::Button),
+            _ => self.filter_event(e),
+        }
+    }
+
     fn set_filter<I: IntoIterator<Item=ConfigInputEvent>>(&mut self, filter: I) {
         filter.into_iter().for_each(|f| { self.filter.insert(f); })
     }
@@ -0,0 +1,223 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use tokio_core::reactor::Handle;
+use tokio_process::CommandExt;
+use futures::{Future, future};
+use failure::Error;
+
+#[derive(Debug, Clone)]
+pub struct Ssh {
+    program: String,
+    destination: String,
+    port: Option<u16>,
+    identity: Option<String>,
+    connect_timeout: u64,
+    options: Vec<String>,
+}
+
+const SSH_CONNECTION_ERROR: i32 = 255;
+
+impl Ssh {
+    pub fn new(program: Option<&str>, host: &str, user: Option<&str>, port: Option<u16>, identity: Option<&str>, connect_timeout: u64, options: &[String]) -> Self {
+        Ssh {
+            program: program.unwrap_or("ssh").to_owned(),
+            destination: if let Some(user) = user {
+                format!("{}@{}", user, host)
+            } else {
+                host.to_owned()
+            },
+            port: port,
+            identity: identity.map(|i| i.to_owned()),
+            connect_timeout: connect_timeout,
+            options: options.to_owned(),
+        }
+    }
+
+    fn control_path() -> PathBuf {
+        env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir)
+            .join("screenstub-ssh-%C")
+    }
+
+    fn options(&self) -> Vec<String> {
+        let mut args = vec![
+            "-o".into(), "BatchMode=yes".into(),
+            "-o".into(), format!("ConnectTimeout={}", self.connect_timeout),
+            // the first connection becomes a master that later commands are multiplexed over
+            "-o".into(), "ControlMaster=auto".into(),
+            "-o".into(), "ControlPersist=yes".into(),
+            "-o".into(), format!("ControlPath={}", Self::control_path().display()),
+        ];
+        if let Some(port) = self.port {
+            args.push("-p".into());
+            args.push(port.to_string());
+        }
+        if let Some(ref identity) = self.identity {
+            args.push("-i".into());
+            args.push(identity.clone());
+        }
+        for option in &self.options {
+            args.push("-o".into());
+            args.push(option.clone());
+        }
+
+        args
+    }
+
+    fn exec_args(&self, args: &[String]) -> Vec<String> {
+        let mut exec_args = self.options();
+        exec_args.push("--".into());
+        exec_args.push(self.destination.clone());
+        exec_args.extend(args.iter().cloned());
+
+        exec_args
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.stdin(Stdio::null())
+            .stdout(Stdio::null());
+
+        command
+    }
+
+    pub fn exec<I: IntoIterator<Item=S>, S: AsRef<str>>(&self, handle: &Handle, args: I) -> Box<Future<Item=(), Error=Error>> {
+        let args: Vec<String> = args.into_iter().map(|s| s.as_ref().to_owned()).collect();
+        let description = format!("{} {:?}", self.destination, args);
+
+        let mut command = self.command();
+        command.args(self.exec_args(&args));
+
+        trace!("ssh exec {}", description);
+        let child = command.spawn_async(handle);
+        Box::new(future::result(child)
+            .and_then(|c| c).map_err(Error::from)
+            .and_then(move |status| match status.code() {
+                Some(0) => {
+                    debug!("ssh {} exited successfully", description);
+                    Ok(())
+                },
+                Some(SSH_CONNECTION_ERROR) =>
+                    Err(format_err!("ssh failed to connect to run {}", description)),
+                Some(code) =>
+                    Err(format_err!("ssh command {} exited with code {}", description, code)),
+                None =>
+                    Err(format_err!("ssh command {} was terminated", description)),
+            })
+        ) as Box<_>
+    }
+
+    pub fn connect(&self, handle: &Handle) -> Box<Future<Item=(), Error=Error>> {
+        self.exec(handle, &["true"])
+    }
+
+    pub fn disconnect(&self, handle: &Handle) -> Box<Future<Item=(), Error=Error>> {
+        let child = self.command()
+            .args(self.options())
+            .arg("-O").arg("exit")
+            .arg(&self.destination)
+            .stderr(Stdio::null())
+            .spawn_async(handle);
+
+        let destination = self.destination.clone();
+        Box::new(future::result(child)
+            .and_then(|c| c).map_err(Error::from)
+            .and_then(move |status| if status.success() {
+                Ok(())
+            } else {
+                Err(format_err!("no ssh master connection to {}", destination))
+            })
+        ) as Box<_>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use std::os::unix::fs::PermissionsExt;
+    use tokio_core::reactor::Core;
+    use super::Ssh;
+
+    // logs each invocation's arguments followed by a blank line
+    const STUB_SSH: &'static str = r#"#!/bin/sh
+printf '%s\n' "$@" "" >> "$(dirname "$0")/args"
+for arg; do
+    case "$arg" in
+        unreachable) exit 255 ;;
+        false) exit 1 ;;
+    esac
+done
+"#;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn exec_args() {
+        let ssh = Ssh::new(None, "vm", Some("user"), Some(2222), Some("~/.ssh/id_vm"), 5, &strings(&["StrictHostKeyChecking=accept-new"]));
+        let control_path = format!("ControlPath={}", Ssh::control_path().display());
+        assert_eq!(ssh.program, "ssh");
+        assert_eq!(ssh.exec_args(&strings(&["ddcutil", "setvcp", "0x60", "15"])), strings(&[
+            "-o", "BatchMode=yes",
+            "-o", "ConnectTimeout=5",
+            "-o", "ControlMaster=auto",
+            "-o", "ControlPersist=yes",
+            "-o", &control_path,
+            "-p", "2222",
+            "-i", "~/.ssh/id_vm",
+            "-o", "StrictHostKeyChecking=accept-new",
+            "--", "user@vm",
+            "ddcutil", "setvcp", "0x60", "15",
+        ]));
+    }
+
+    #[test]
+    fn exec_args_defaults() {
+        let ssh = Ssh::new(Some("/usr/bin/ssh"), "vm", None, None, None, 10, &[]);
+        let args = ssh.exec_args(&strings(&["true"]));
+        assert_eq!(ssh.program, "/usr/bin/ssh");
+        assert_eq!(&args[args.len() - 3..], &strings(&["--", "vm", "true"])[..]);
+        assert!(!args.contains(&"-p".to_owned()));
+        assert!(!args.contains(&"-i".to_owned()));
+    }
+
+    #[test]
+    fn stub_process() {
+        let dir = env::temp_dir().join(format!("screenstub-ssh-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("ssh");
+        fs::write(&program, STUB_SSH).unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        let program = program.to_str().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let ssh = Ssh::new(Some(program), "vm", None, None, None, 5, &[]);
+        core.run(ssh.connect(&handle)).unwrap();
+        let e = core.run(ssh.exec(&handle, &["false"])).unwrap_err();
+        assert!(e.to_string().contains("exited with code 1"), "{}", e);
+        let unreachable = Ssh::new(Some(program), "unreachable", None, None, None, 5, &[]);
+        let e = core.run(unreachable.exec(&handle, &["true"])).unwrap_err();
+        assert!(e.to_string().contains("failed to connect"), "{}", e);
+        core.run(ssh.disconnect(&handle)).unwrap();
+
+        let args = fs::read_to_string(dir.join("args")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let calls: Vec<Vec<&str>> = args.split("\n\n")
+            .filter(|call| !call.is_empty())
+            .map(|call| call.lines().collect())
+            .collect();
+        assert_eq!(calls.len(), 4);
+
+        let control_path = format!("ControlPath={}", Ssh::control_path().display());
+        for call in &calls {
+            assert!(call.contains(&"ControlMaster=auto"));
+            assert!(call.contains(&&control_path[..]));
+        }
+        assert_eq!(&calls[0][calls[0].len() - 3..], &["--", "vm", "true"]);
+        assert_eq!(&calls[1][calls[1].len() - 3..], &["--", "vm", "false"]);
+        assert_eq!(&calls[3][calls[3].len() - 3..], &["-O", "exit", "vm"]);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use failure::Error;
+use config::{ConfigExec, ConfigExecCommand};
+
+#[derive(Debug, Clone)]
+pub enum TemplateValue {
+    Number(u64),
+    String(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TemplateVars {
+    vars: HashMap<&'static str, Option<TemplateValue>>,
+}
+
+impl TemplateVars {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn number<N: Into<u64>>(&mut self, name: &'static str, value: Option<N>) -> &mut Self {
+        self.vars.insert(name, value.map(|v| TemplateValue::Number(v.into())));
+
+        self
+    }
+
+    pub fn string<S: Into<String>>(&mut self, name: &'static str, value: Option<S>) -> &mut Self {
+        self.vars.insert(name, value.map(|v| TemplateValue::String(v.into())));
+
+        self
+    }
+
+    // leaves placeholders for this variable as they are
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        self.vars.remove(name);
+
+        self
+    }
+
+    // exec events predate templates, so their args are only rendered when the command opts in
+    pub fn render_exec(&self, exec: &ConfigExec) -> Result<ConfigExecCommand, Error> {
+        let mut command = exec.command();
+        if command.template {
+            command.args = self.render_all(&command.args)?;
+        }
+
+        Ok(command)
+    }
+
+    pub fn render_all<I: IntoIterator<Item=S>, S: AsRef<str>>(&self, templates: I) -> Result<Vec<String>, Error> {
+        templates.into_iter().map(|t| self.render(t.as_ref())).collect()
+    }
+
+    pub fn render(&self, template: &str) -> Result<String, Error> {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(i) = rest.find(|c| c == '{' || c == '}') {
+            out.push_str(&rest[..i]);
+            let tail = &rest[i..];
+
+            rest = if tail.starts_with("{{") {
+                out.push('{');
+                &tail[2..]
+            } else if tail.starts_with("}}") {
+                out.push('}');
+                &tail[2..]
+            } else if tail.starts_with('{') {
+                match self.placeholder(&tail[1..]) {
+                    Some((name, spec, len)) => {
+                        self.format(&mut out, name, spec)?;
+                        &tail[1 + len..]
+                    },
+                    None => {
+                        // not a known variable, so leave things like shell braces alone
+                        out.push('{');
+                        &tail[1..]
+                    },
+                }
+            } else {
+                out.push('}');
+                &tail[1..]
+            };
+        }
+        out.push_str(rest);
+
+        Ok(out)
+    }
+
+    fn placeholder<'a>(&self, s: &'a str) -> Option<(&'a str, &'a str, usize)> {
+        let end = s.find('}')?;
+        let inner = &s[..end];
+        let (name, spec) = match inner.find(':') {
+            Some(i) => (&inner[..i], &inner[i + 1..]),
+            None => (inner, ""),
+        };
+        // "{}" and "{:x}" refer to the input value being switched to
+        let name = if name.is_empty() { "value" } else { name };
+
+        if self.vars.contains_key(name) && spec.chars().all(|c| c.is_ascii_alphanumeric()) {
+            Some((name, spec, end + 1))
+        } else {
+            None
+        }
+    }
+
+    fn format(&self, out: &mut String, name: &str, spec: &str) -> Result<(), Error> {
+        match self.vars.get(name) {
+            None => Err(format_err!("unknown template variable {{{}}}", name)),
+            Some(&None) => Err(format_err!("template variable {{{}}} is not available", name)),
+            Some(&Some(TemplateValue::String(ref value))) => if spec.is_empty() {
+                out.push_str(value);
+                Ok(())
+            } else {
+                Err(format_err!("template variable {{{}}} is not a number", name))
+            },
+            Some(&Some(TemplateValue::Number(value))) => {
+                let (kind, width) = match spec.chars().last() {
+                    Some(c @ 'x') | Some(c @ 'X') | Some(c @ 'd') => (c, &spec[..spec.len() - 1]),
+                    _ => ('d', spec),
+                };
+                let (zero, width) = if width.is_empty() {
+                    // VCP values are bytes, keep hex formatting compatible with "{:x}"
+                    (kind != 'd', if kind == 'd' { 0 } else { 2 })
+                } else {
+                    (width.starts_with('0'), width.parse::<usize>().map_err(|_| format_err!("invalid template format {:?}", spec))?)
+                };
+
+                out.push_str(&match (kind, zero) {
+                    ('x', true) => format!("{:01$x}", value, width),
+                    ('x', false) => format!("{:1$x}", value, width),
+                    ('X', true) => format!("{:01$X}", value, width),
+                    ('X', false) => format!("{:1$X}", value, width),
+                    (_, true) => format!("{:01$}", value, width),
+                    (_, false) => format!("{:1$}", value, width),
+                });
+                Ok(())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{ConfigExec, ConfigExecCommand};
+    use super::TemplateVars;
+
+    fn vars() -> TemplateVars {
+        let mut vars = TemplateVars::new();
+        vars.number("value", Some(0x0fu8))
+            .number("bus", Some(5u8))
+            .number("host", None::<u8>)
+            .string("monitor", Some("LG Ultra HD"));
+        vars
+    }
+
+    #[test]
+    fn render_numbers() {
+        let vars = vars();
+        assert_eq!(vars.render("{}").unwrap(), "15");
+        assert_eq!(vars.render("{value}").unwrap(), "15");
+        assert_eq!(vars.render("0x{:x}").unwrap(), "0x0f");
+        assert_eq!(vars.render("{value:X}").unwrap(), "0F");
+        assert_eq!(vars.render("{value:03d}").unwrap(), "015");
+        assert_eq!(vars.render("{value:4}").unwrap(), "  15");
+        assert_eq!(vars.render("{value:1x}").unwrap(), "f");
+        assert_eq!(vars.render("/dev/i2c-{bus}").unwrap(), "/dev/i2c-5");
+    }
+
+    #[test]
+    fn render_strings() {
+        let vars = vars();
+        assert_eq!(vars.render("--monitor={monitor}").unwrap(), "--monitor=LG Ultra HD");
+        assert!(vars.render("{monitor:x}").is_err());
+    }
+
+    #[test]
+    fn render_braces() {
+        let vars = vars();
+        assert_eq!(vars.render("{{}}").unwrap(), "{}");
+        assert_eq!(vars.render("{{value}}").unwrap(), "{value}");
+        assert_eq!(vars.render("${HOME}").unwrap(), "${HOME}");
+        assert_eq!(vars.render("{unknown}").unwrap(), "{unknown}");
+        assert_eq!(vars.render("{").unwrap(), "{");
+    }
+
+    #[test]
+    fn render_unavailable() {
+        let vars = vars();
+        assert!(vars.render("{host}").is_err());
+        assert!(vars.render("{value:zz}").is_err());
+    }
+
+    #[test]
+    fn render_removed() {
+        let mut vars = vars();
+        vars.remove("value");
+        assert_eq!(vars.render("find -exec rm {} ;").unwrap(), "find -exec rm {} ;");
+        assert_eq!(vars.render("{bus}").unwrap(), "5");
+    }
+
+    #[test]
+    fn render_exec() {
+        let vars = vars();
+        let args: Vec<String> = ["awk", "{{print $1}}", "{bus}"].iter().map(|&s| s.to_owned()).collect();
+        let legacy = ConfigExec::Args(args.clone());
+        assert_eq!(vars.render_exec(&legacy).unwrap().args, args);
+
+        let templated = ConfigExec::Command(ConfigExecCommand {
+            args: args,
+            template: true,
+            .. Default::default()
+        });
+        assert_eq!(vars.render_exec(&templated).unwrap().args, vec!["awk", "{print $1}", "5"]);
+    }
+}
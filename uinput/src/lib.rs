@@ -15,9 +15,12 @@ use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::{self, Write};
 use std::{mem, slice};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
 use input::{
     UInputHandle, EvdevHandle, InputId,
-    InputEvent, EventKind,
+    InputEvent, EventKind, EventTime, LedKind,
     AbsoluteAxis, RelativeAxis, Key,
     AbsoluteInfoSetup, AbsoluteInfo, Bitmask,
 };
@@ -101,6 +104,11 @@ impl Builder {
         // autorepeat is undesired, the VM will have its own implementation
         //self.bits_events.set(EventKind::Autorepeat); // kernel should handle this for us as long as it's set
         self.bits_keys.or(Key::iter());
+        // the VM reports its lock state back through these
+        self.bits_events.set(EventKind::Led);
+        self.bits_led.set(LedKind::NumLock);
+        self.bits_led.set(LedKind::CapsLock);
+        self.bits_led.set(LedKind::ScrollLock);
     }
 
     pub fn from_evdev(&mut self, evdev: &EvdevHandle) -> io::Result<&mut Self> {
@@ -230,10 +238,66 @@ impl UInput {
             buffer_read: Default::default(),
             eof: false,
             is_readable: false,
+            frame_time: None,
+            written_time: None,
+            latency: Default::default(),
         })
     }
 }
 
+// time from the X server reporting input until it's written to the device,
+// assuming X timestamps come from the monotonic clock. X only reports
+// milliseconds, so this has a resolution of 1 ms.
+#[derive(Debug, Default)]
+pub struct Latency {
+    frames: Cell<u64>,
+    // moving average in microseconds
+    average: Cell<u64>,
+    max: Cell<u64>,
+}
+
+impl Latency {
+    // anything longer can't be a timestamp from the same clock
+    const MAX_MS: u32 = 10000;
+
+    pub fn frames(&self) -> u64 {
+        self.frames.get()
+    }
+
+    pub fn average(&self) -> Duration {
+        Duration::from_micros(self.average.get())
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max.get())
+    }
+
+    fn record(&self, time: &EventTime) {
+        let now = unsafe {
+            let mut now: libc::timespec = mem::zeroed();
+            libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+            now.tv_sec as u64 * 1000000 + now.tv_nsec as u64 / 1000
+        };
+
+        // X timestamps are milliseconds that wrap around
+        let time_ms = (time.seconds() as u64 * 1000 + time.microseconds() as u64 / 1000) as u32;
+        let elapsed_ms = ((now / 1000) as u32).wrapping_sub(time_ms);
+        if elapsed_ms > Self::MAX_MS {
+            return
+        }
+
+        let latency = elapsed_ms as u64 * 1000;
+        let frames = self.frames.get();
+        self.average.set(if frames == 0 {
+            latency
+        } else {
+            (self.average.get() * 15 + latency) / 16
+        });
+        self.max.set(self.max.get().max(latency));
+        self.frames.set(frames + 1);
+    }
+}
+
 #[derive(Debug)]
 pub struct Evdev {
     pub fd: Fd<File>,
@@ -288,6 +352,17 @@ pub struct UInputSink {
     buffer_read: BytesMut,
     eof: bool,
     is_readable: bool,
+    // the first timestamp of the frame being buffered
+    frame_time: Option<EventTime>,
+    // the timestamp of a complete frame still waiting to be written out
+    written_time: Option<EventTime>,
+    latency: Rc<Latency>,
+}
+
+impl UInputSink {
+    pub fn latency(&self) -> Rc<Latency> {
+        self.latency.clone()
+    }
 }
 
 impl Sink for UInputSink {
@@ -296,9 +371,22 @@ impl Sink for UInputSink {
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
         trace!("UInputSink start_send({:?})", item);
+        let is_sync = item.kind == EventKind::Synchronize;
+        if self.frame_time.is_none() && (item.time.seconds() != 0 || item.time.microseconds() != 0) {
+            self.frame_time = Some(item.time);
+        }
         EventCodec::new().encode(item, &mut self.buffer_write)?;
 
-        self.poll_complete()?;
+        // write each frame at once, up to its SYN_REPORT
+        if is_sync {
+            if let Some(time) = self.frame_time.take() {
+                // keep the oldest frame if the previous one hasn't drained yet
+                if self.written_time.is_none() {
+                    self.written_time = Some(time);
+                }
+            }
+            self.poll_complete()?;
+        }
 
         Ok(AsyncSink::Ready)
     }
@@ -316,6 +404,10 @@ impl Sink for UInputSink {
             let _ = self.buffer_write.split_to(n);
         }
 
+        if let Some(time) = self.written_time.take() {
+            self.latency.record(&time);
+        }
+
         //try_nb!(self.write.flush());
 
         Ok(Async::Ready(()))
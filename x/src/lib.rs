@@ -4,19 +4,37 @@ extern crate failure;
 #[macro_use]
 extern crate log;
 pub extern crate xcb;
+extern crate libc;
 
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::fmt;
-use futures::sync::mpsc::{Sender, Receiver, TrySendError};
+use std::time::Duration;
+use std::thread;
+use futures::sync::mpsc::{Sender, Receiver};
 use futures::executor::{self, Notify, NotifyHandle};
-use futures::Async;
+use futures::{Async, AsyncSink};
 use failure::Error;
 
+mod xinput;
+mod randr;
+
+pub use randr::XMonitor;
+
+const XK_NUM_LOCK: xcb::Keysym = 0xff7f;
+// excludes the pointer button masks
+const MOD_MASK_KEYBOARD: u16 = 0xff;
+const STATUS_MARGIN: i16 = 8;
+// how often X is checked for new events while the reactor is falling behind
+const PENDING_POLL_MS: u64 = 2;
+// the lowest min_keycode allowed by the protocol, so higher key codes may not fit in an X keycode
+pub const MAX_KEYCODE: u16 = 0xff - 8;
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct XState {
     pub width: u16,
     pub height: u16,
     pub grabbed: bool,
+    pub devices_grabbed: bool,
     pub running: bool,
 }
 
@@ -29,18 +47,74 @@ pub enum XEvent {
     Mouse {
         x: i16,
         y: i16,
+        time: xcb::Timestamp,
+    },
+    MouseRelative {
+        x: i32,
+        y: i32,
+        time: xcb::Timestamp,
     },
     Button {
         pressed: bool,
         button: xcb::Button,
         state: u16,
+        time: xcb::Timestamp,
     },
     Key {
         pressed: bool,
         keycode: xcb::Keycode,
         keysym: Option<xcb::Keysym>,
         state: u16,
+        time: xcb::Timestamp,
     },
+    Hotkey {
+        id: usize,
+        pressed: bool,
+    },
+    Keymap {
+        keysyms: Vec<xcb::Keysym>,
+        keysyms_per_keycode: u8,
+    },
+    // text read from a selection in response to XRequest::GetSelection
+    Selection(String),
+    LockState(XLockState),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct XLockState {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XSelection {
+    Clipboard,
+    Primary,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct XWindowConfig {
+    pub windowed: bool,
+    // defaults to the size of the screen
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub title: Option<String>,
+    pub class: Option<String>,
+    // places the window on the output this monitor is connected to
+    pub monitor: XMonitor,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum XHotkeyKey {
+    Keycode(xcb::Keycode),
+    Keysym(xcb::Keysym),
+}
+
+#[derive(Debug, Clone)]
+pub struct XHotkey {
+    pub id: usize,
+    pub key: XHotkeyKey,
+    pub modifiers: u16,
 }
 
 #[derive(Debug)]
@@ -48,57 +122,108 @@ pub enum XRequest {
     Quit,
     UnstickHost,
     UnstickGuest,
-    Grab,
+    Grab {
+        // report relative motion from XInput2 raw events instead of the pointer position
+        raw_motion: bool,
+        // hide the cursor and keep warping it back to the center, reporting relative motion
+        pointer_lock: bool,
+    },
     Ungrab,
+    GrabHotkeys(Vec<XHotkey>),
+    // grabs the named slave devices through XInput2, so that only their input goes to the guest
+    GrabDevices(Vec<String>),
+    UngrabDevices,
+    // lines of text to show in the window
+    SetStatus(Vec<String>),
+    // reads the first of these selections that holds any text
+    GetSelection(Vec<XSelection>),
+    // takes ownership of the selections and offers this text to other clients
+    SetSelection(Vec<XSelection>, String),
+    // locks or unlocks the host keyboard modifiers to match
+    SetLockState(XLockState),
 }
 
 pub struct XContext {
     conn: xcb::Connection,
     window: u32,
+    root: u32,
+    window_config: XWindowConfig,
 
     keys: xcb::GetKeyboardMappingReply,
     mods: xcb::GetModifierMappingReply,
     state: XState,
     next_event: Option<xcb::GenericEvent>,
+    hotkeys: Vec<XHotkey>,
+    hotkey_grabs: Vec<(usize, xcb::Keycode, u16)>,
+    hotkeys_pressed: Vec<(xcb::Keycode, usize)>,
+    xinput_opcode: Option<u8>,
+    device_grabs: Vec<xinput::DeviceId>,
+    raw_motion: bool,
+    raw_remainder: (f64, f64),
+    pointer_lock: bool,
+    blank_cursor: xcb::Cursor,
+    status: Vec<String>,
+    status_gc: xcb::Gcontext,
+    // font ascent and descent
+    status_font: (i16, i16),
+    selection_pending: Vec<XSelection>,
+    selection_text: Option<String>,
+    xkb_event: Option<u8>,
+    lock_state: XLockState,
+    randr_event: Option<u8>,
+    output: Option<randr::OutputGeometry>,
 
     atom_wm_state: xcb::Atom,
     atom_wm_protocols: xcb::Atom,
     atom_wm_delete_window: xcb::Atom,
     atom_net_wm_state: xcb::Atom,
     atom_net_wm_state_fullscreen: xcb::Atom,
+    atom_net_wm_name: xcb::Atom,
+    atom_utf8_string: xcb::Atom,
+    atom_clipboard: xcb::Atom,
+    atom_targets: xcb::Atom,
+    atom_incr: xcb::Atom,
+    atom_selection: xcb::Atom,
+    atom_edid: xcb::Atom,
     atom_atom: xcb::Atom,
 }
 
-pub trait SpinSendValue {
-    fn skip_threshold(&self) -> Option<usize>;
-}
-
-impl SpinSendValue for Result<XEvent, Error> {
-    fn skip_threshold(&self) -> Option<usize> {
-        match *self {
-            Ok(XEvent::Mouse { .. }) => Some(0),
-            Ok(XEvent::Key { .. }) => Some(1),
-            Ok(XEvent::Button { .. }) => Some(4),
-            Err(..) => Some(0x20),
-            _ => Some(0x10),
-        }
-    }
-}
-
 impl XContext {
-    pub fn connect() -> Result<Self, Error> {
+    pub fn connect(window_config: XWindowConfig) -> Result<Self, Error> {
         let (conn, screen_num) = xcb::Connection::connect(None)?;
         let window = conn.generate_id();
-        let (keys, mods) = {
+        let randr_event = randr::first_event(&conn);
+        let atom_edid = xcb::intern_atom(&conn, false, "EDID").get_reply()?.atom();
+        let (root, keys, mods, status_gc, status_font, output) = {
             let setup = conn.get_setup();
             let screen = setup.roots().nth(screen_num as usize).unwrap();
 
+            let output = match randr_event {
+                Some(..) if !window_config.monitor.is_empty() =>
+                    randr::find_output(&conn, screen.root(), atom_edid, &window_config.monitor)?,
+                None if !window_config.monitor.is_empty() => {
+                    warn!("RandR is not available, the window can't be placed on the monitor");
+                    None
+                },
+                _ => None,
+            };
+            if output.is_none() && randr_event.is_some() && !window_config.monitor.is_empty() {
+                warn!("monitor not found among the connected outputs, using the whole screen");
+            }
+            let (x, y, width, height) = Self::window_geometry(&window_config, output.unwrap_or(randr::OutputGeometry {
+                x: 0,
+                y: 0,
+                width: screen.width_in_pixels(),
+                height: screen.height_in_pixels(),
+            }));
+
             xcb::create_window(&conn,
                 xcb::COPY_FROM_PARENT as _,
                 window,
                 screen.root(),
-                0, 0,
-                screen.width_in_pixels(), screen.height_in_pixels(),
+                x, y,
+                width,
+                height,
                 0,
                 xcb::WINDOW_CLASS_INPUT_OUTPUT as _,
                 screen.root_visual(),
@@ -110,36 +235,177 @@ impl XContext {
                         xcb::EVENT_MASK_KEY_PRESS | xcb::EVENT_MASK_KEY_RELEASE |
                         xcb::EVENT_MASK_BUTTON_PRESS | xcb::EVENT_MASK_BUTTON_RELEASE |
                         xcb::EVENT_MASK_POINTER_MOTION | xcb::EVENT_MASK_BUTTON_MOTION |
-                        xcb::EVENT_MASK_STRUCTURE_NOTIFY | xcb::EVENT_MASK_FOCUS_CHANGE
+                        xcb::EVENT_MASK_STRUCTURE_NOTIFY | xcb::EVENT_MASK_FOCUS_CHANGE |
+                        xcb::EVENT_MASK_EXPOSURE
                     ),
                 ]
             );
 
+            let font = conn.generate_id();
+            let gc = conn.generate_id();
+            xcb::open_font(&conn, font, "fixed");
+            xcb::create_gc(&conn, gc, window, &[
+                (xcb::GC_FOREGROUND, screen.white_pixel()),
+                (xcb::GC_BACKGROUND, screen.black_pixel()),
+                (xcb::GC_FONT, font),
+            ]);
+            let font_info = xcb::query_font(&conn, font).get_reply()?;
+            xcb::close_font(&conn, font);
+
             (
+                screen.root(),
                 xcb::get_keyboard_mapping(&conn, setup.min_keycode(), setup.max_keycode() - setup.min_keycode()).get_reply()?,
                 xcb::get_modifier_mapping(&conn).get_reply()?,
+                gc,
+                (font_info.font_ascent(), font_info.font_descent()),
+                output,
             )
         };
 
+        if randr_event.is_some() {
+            randr::select_events(&conn, root)?;
+        }
+
+        // 1x1 cursor with an empty mask, shown while the pointer is locked
+        let blank_cursor = {
+            let pixmap = conn.generate_id();
+            let gc = conn.generate_id();
+            let cursor = conn.generate_id();
+            xcb::create_pixmap(&conn, 1, pixmap, window, 1, 1);
+            xcb::create_gc(&conn, gc, pixmap, &[(xcb::GC_FOREGROUND, 0)]);
+            xcb::poly_fill_rectangle(&conn, pixmap, gc, &[xcb::Rectangle::new(0, 0, 1, 1)]);
+            xcb::create_cursor(&conn, cursor, pixmap, pixmap, 0, 0, 0, 0, 0, 0, 0, 0);
+            xcb::free_gc(&conn, gc);
+            xcb::free_pixmap(&conn, pixmap);
+            cursor
+        };
+
+        let xinput_opcode = xinput::opcode(&conn);
+        if xinput_opcode.is_none() {
+            warn!("XInput2 is not available, device grabs will not work");
+        }
+
+        let xkb_event = match xcb::xkb::use_extension(&conn, 1, 0).get_reply() {
+            Ok(ref r) if r.supported() => {
+                let event_type = xcb::xkb::EVENT_TYPE_STATE_NOTIFY as u16;
+                xcb::xkb::select_events(&conn, xcb::xkb::ID_USE_CORE_KBD as _, event_type, 0, event_type, 0, 0, None).request_check()?;
+                Some(xcb::query_extension(&conn, "XKEYBOARD").get_reply()?.first_event())
+            },
+            _ => {
+                warn!("XKB is not available, lock state will not be synchronized");
+                None
+            },
+        };
+
         Ok(XContext {
             atom_wm_state: xcb::intern_atom(&conn, true, "WM_STATE").get_reply()?.atom(),
             atom_wm_protocols: xcb::intern_atom(&conn, true, "WM_PROTOCOLS").get_reply()?.atom(),
             atom_wm_delete_window: xcb::intern_atom(&conn, true, "WM_DELETE_WINDOW").get_reply()?.atom(),
             atom_net_wm_state: xcb::intern_atom(&conn, true, "_NET_WM_STATE").get_reply()?.atom(),
             atom_net_wm_state_fullscreen: xcb::intern_atom(&conn, true, "_NET_WM_STATE_FULLSCREEN").get_reply()?.atom(),
+            atom_net_wm_name: xcb::intern_atom(&conn, false, "_NET_WM_NAME").get_reply()?.atom(),
+            atom_utf8_string: xcb::intern_atom(&conn, false, "UTF8_STRING").get_reply()?.atom(),
+            atom_clipboard: xcb::intern_atom(&conn, false, "CLIPBOARD").get_reply()?.atom(),
+            atom_targets: xcb::intern_atom(&conn, false, "TARGETS").get_reply()?.atom(),
+            atom_incr: xcb::intern_atom(&conn, false, "INCR").get_reply()?.atom(),
+            atom_selection: xcb::intern_atom(&conn, false, "SCREENSTUB_SELECTION").get_reply()?.atom(),
+            atom_edid: atom_edid,
             atom_atom: xcb::intern_atom(&conn, true, "ATOM").get_reply()?.atom(),
 
             keys: keys,
             mods: mods,
             state: Default::default(),
             next_event: None,
+            hotkeys: Default::default(),
+            hotkey_grabs: Default::default(),
+            hotkeys_pressed: Default::default(),
+            xinput_opcode: xinput_opcode,
+            device_grabs: Default::default(),
+            raw_motion: false,
+            raw_remainder: (0.0, 0.0),
+            pointer_lock: false,
+            blank_cursor: blank_cursor,
+            status: Default::default(),
+            status_gc: status_gc,
+            status_font: status_font,
+            selection_pending: Default::default(),
+            selection_text: None,
+            xkb_event: xkb_event,
+            lock_state: Default::default(),
+            randr_event: randr_event,
+            output: output,
 
             conn: conn,
             window: window,
+            root: root,
+            window_config: window_config,
         })
     }
 
+    // covers the output, or centers a window of the configured size on it
+    fn window_geometry(window_config: &XWindowConfig, output: randr::OutputGeometry) -> (i16, i16, u16, u16) {
+        let width = window_config.width.unwrap_or(output.width);
+        let height = window_config.height.unwrap_or(output.height);
+        (
+            output.x + (output.width.saturating_sub(width) / 2) as i16,
+            output.y + (output.height.saturating_sub(height) / 2) as i16,
+            width,
+            height,
+        )
+    }
+
+    fn set_position_hints(&self) -> Result<(), Error> {
+        let output = match self.output {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+
+        // WM_SIZE_HINTS with only the user specified position and size set
+        let (x, y, width, height) = Self::window_geometry(&self.window_config, output);
+        let us_position = 1;
+        let us_size = 2;
+        let mut hints = [0u32; 18];
+        hints[0] = us_position | us_size;
+        hints[1] = x as i32 as u32;
+        hints[2] = y as i32 as u32;
+        hints[3] = width as u32;
+        hints[4] = height as u32;
+        xcb::change_property(&self.conn,
+            xcb::PROP_MODE_REPLACE as _,
+            self.window,
+            xcb::ATOM_WM_NORMAL_HINTS,
+            xcb::ATOM_WM_SIZE_HINTS, 32,
+            &hints
+        ).request_check()?;
+
+        Ok(())
+    }
+
+    fn process_randr_event(&mut self) -> Result<(), Error> {
+        let output = randr::find_output(&self.conn, self.root, self.atom_edid, &self.window_config.monitor)?;
+        if output == self.output || output.is_none() {
+            // keep the window where it is while the monitor is disconnected
+            return Ok(())
+        }
+
+        self.output = output;
+        self.set_position_hints()?;
+        if let Some(output) = output {
+            let (x, y, width, height) = Self::window_geometry(&self.window_config, output);
+            xcb::configure_window(&self.conn, self.window, &[
+                (xcb::CONFIG_WINDOW_X as u16, x as i32 as u32),
+                (xcb::CONFIG_WINDOW_Y as u16, y as i32 as u32),
+                (xcb::CONFIG_WINDOW_WIDTH as u16, width as u32),
+                (xcb::CONFIG_WINDOW_HEIGHT as u16, height as u32),
+            ]).request_check()?;
+        }
+
+        Ok(())
+    }
+
     pub fn map_window(&self) -> Result<(), Error> {
+        self.set_position_hints()?;
+
         xcb::change_property(&self.conn,
             xcb::PROP_MODE_REPLACE as _,
             self.window,
@@ -148,14 +414,44 @@ impl XContext {
             &[self.atom_wm_delete_window]
         ).request_check()?;
 
+        let title = self.window_config.title.as_ref().map(|t| &t[..]).unwrap_or("screenstub");
         xcb::change_property(&self.conn,
-            xcb::PROP_MODE_APPEND as _,
+            xcb::PROP_MODE_REPLACE as _,
             self.window,
-            self.atom_net_wm_state,
-            self.atom_atom, 32,
-            &[self.atom_net_wm_state_fullscreen]
+            xcb::ATOM_WM_NAME,
+            xcb::ATOM_STRING, 8,
+            title.as_bytes()
         ).request_check()?;
 
+        xcb::change_property(&self.conn,
+            xcb::PROP_MODE_REPLACE as _,
+            self.window,
+            self.atom_net_wm_name,
+            self.atom_utf8_string, 8,
+            title.as_bytes()
+        ).request_check()?;
+
+        // instance and class names, both null terminated
+        let class = self.window_config.class.as_ref().map(|c| &c[..]).unwrap_or("screenstub");
+        let class = format!("{}\0{}\0", class, class);
+        xcb::change_property(&self.conn,
+            xcb::PROP_MODE_REPLACE as _,
+            self.window,
+            xcb::ATOM_WM_CLASS,
+            xcb::ATOM_STRING, 8,
+            class.as_bytes()
+        ).request_check()?;
+
+        if !self.window_config.windowed {
+            xcb::change_property(&self.conn,
+                xcb::PROP_MODE_APPEND as _,
+                self.window,
+                self.atom_net_wm_state,
+                self.atom_atom, 32,
+                &[self.atom_net_wm_state_fullscreen]
+            ).request_check()?;
+        }
+
         xcb::map_window(&self.conn, self.window);
 
         self.flush()?;
@@ -206,6 +502,21 @@ impl XContext {
         }
     }
 
+    pub fn poll_event(&mut self) -> Result<Option<xcb::GenericEvent>, Error> {
+        match self.next_event.take().or_else(|| self.conn.poll_for_event()) {
+            Some(event) => Ok(Some(event)),
+            None => match self.connection_error() {
+                Some(err) => Err(err.into()),
+                None => Ok(None),
+            },
+        }
+    }
+
+    // doesn't read from the connection
+    pub fn poll_queued_event(&mut self) -> Option<xcb::GenericEvent> {
+        self.next_event.take().or_else(|| self.conn.poll_for_queued_event())
+    }
+
     pub fn peek(&mut self) -> Option<&xcb::GenericEvent> {
         if self.next_event.is_none() {
             if let Some(event) = self.conn.poll_for_event() {
@@ -222,81 +533,486 @@ impl XContext {
         code - self.conn.get_setup().min_keycode()
     }
 
-    pub fn keysym(&self, code: xcb::Keycode) -> Option<xcb::Keysym> {
-        let modifier = 0; // TODO: ?
-        match self.keys.keysyms().get(code as usize * self.keys.keysyms_per_keycode() as usize + modifier).cloned() {
-            Some(0) => None,
-            keysym => keysym,
+    pub fn x_keycode(&self, code: xcb::Keycode) -> Result<xcb::Keycode, Error> {
+        // X keycodes are a byte wide and start at min_keycode, so the last few evdev codes don't fit
+        code.checked_add(self.conn.get_setup().min_keycode())
+            .ok_or_else(|| format_err!("key code {} has no X keycode", code))
+    }
+
+    fn modifier_mask(&self, keysym: xcb::Keysym) -> u16 {
+        let min_keycode = self.conn.get_setup().min_keycode();
+        let keysyms_per_keycode = self.keys.keysyms_per_keycode() as usize;
+        let keysyms = self.keys.keysyms();
+        self.mods.keycodes().chunks(self.mods.keycodes_per_modifier() as usize).enumerate()
+            .filter(|&(_, codes)| codes.iter().any(|&code| code >= min_keycode && {
+                let start = (code - min_keycode) as usize * keysyms_per_keycode;
+                keysyms.get(start..start + keysyms_per_keycode).map(|k| k.contains(&keysym)).unwrap_or(false)
+            })).fold(0, |mask, (i, _)| mask | 1 << i)
+    }
+
+    fn lock_masks(&self) -> [u16; 4] {
+        let num_lock = self.modifier_mask(XK_NUM_LOCK);
+        let caps_lock = xcb::MOD_MASK_LOCK as u16;
+        [0, caps_lock, num_lock, caps_lock | num_lock]
+    }
+
+    fn lock_state_from_mods(&self, mods: u8) -> XLockState {
+        let mods = mods as u16;
+        XLockState {
+            caps_lock: mods & xcb::MOD_MASK_LOCK as u16 != 0,
+            num_lock: mods & self.modifier_mask(XK_NUM_LOCK) != 0,
         }
     }
 
-    pub fn stop(&mut self) {
-        self.state.running = false;
+    pub fn lock_state(&mut self) -> Result<XEvent, Error> {
+        if self.xkb_event.is_some() {
+            let mods = xcb::xkb::get_state(&self.conn, xcb::xkb::ID_USE_CORE_KBD as _).get_reply()?.locked_mods();
+            self.lock_state = self.lock_state_from_mods(mods);
+        }
+
+        Ok(XEvent::LockState(self.lock_state))
     }
 
-    fn spin_send_once<T>(sender: &mut Sender<T>, value:T) -> Result<Option<T>, TrySendError<T>> {
-        match sender.try_send(value) {
-            Ok(..) => Ok(None),
-            Err(err) => {
-                if err.is_full() {
-                    Ok(Some(err.into_inner()))
-                } else {
-                    Err(err)
-                }
-            },
+    fn set_lock_state(&mut self, locks: XLockState) -> Result<(), Error> {
+        if self.xkb_event.is_none() {
+            return Ok(())
         }
+
+        let caps_lock = xcb::MOD_MASK_LOCK as u8;
+        let num_lock = self.modifier_mask(XK_NUM_LOCK) as u8;
+        let mods =
+            if locks.caps_lock { caps_lock } else { 0 } |
+            if locks.num_lock { num_lock } else { 0 };
+        xcb::xkb::latch_lock_state(&self.conn, xcb::xkb::ID_USE_CORE_KBD as _,
+            caps_lock | num_lock, mods,
+            false, 0, 0, false, 0
+        ).request_check()?;
+
+        Ok(())
     }
 
-    pub fn spin_send<T: fmt::Debug + SpinSendValue>(sender: &mut Sender<T>, value: T) -> Result<(), TrySendError<T>> {
-        use std::thread::sleep;
-        use std::time::Duration;
+    fn process_xkb_event(&mut self, event: &xcb::GenericEvent) -> Option<XEvent> {
+        let event = unsafe { xcb::cast_event::<xcb::xkb::StateNotifyEvent>(event) };
+        if event.xkb_type() != xcb::xkb::STATE_NOTIFY {
+            return None
+        }
 
-        trace!("X spin sending {:?}", value);
+        let locks = self.lock_state_from_mods(event.locked_mods());
+        if locks == self.lock_state {
+            None
+        } else {
+            self.lock_state = locks;
+            Some(XEvent::LockState(locks))
+        }
+    }
+
+    fn ungrab_hotkeys(&mut self) -> Result<(), Error> {
+        for &(_, keycode, _) in &self.hotkey_grabs {
+            xcb::ungrab_key(&self.conn, self.x_keycode(keycode)?, self.root, xcb::MOD_MASK_ANY as _).request_check()?;
+        }
+        self.hotkey_grabs.clear();
+        self.hotkeys_pressed.clear();
+
+        Ok(())
+    }
+
+    fn grab_hotkeys(&mut self, hotkeys: &[XHotkey]) -> Result<(), Error> {
+        self.ungrab_hotkeys()?;
+        self.hotkeys = hotkeys.to_owned();
+
+        let locks = self.lock_masks();
+        for hotkey in hotkeys {
+            let keycode = match hotkey.key {
+                XHotkeyKey::Keycode(keycode) => keycode,
+                XHotkeyKey::Keysym(keysym) => match self.keysym_keycode(keysym) {
+                    Some(keycode) => keycode,
+                    None => {
+                        warn!("no key found for global hotkey {:?} in the current layout", hotkey);
+                        continue
+                    },
+                },
+            };
+            let x_keycode = match self.x_keycode(keycode) {
+                Ok(x_keycode) => x_keycode,
+                Err(e) => {
+                    warn!("can't grab global hotkey {:?}: {}", hotkey, e);
+                    continue
+                },
+            };
 
-        let mut count = 0;
-        let mut value = Some(value);
-        while let Some(v) = match value.take() {
-            None => None,
-            Some(v) => Self::spin_send_once(sender, v)?,
-        } {
-            if count == 0 {
-                warn!("failed to queue X event");
+            // the grab only matches exact modifier state, so cover every lock combination
+            let grabbed = locks.iter().map(|&lock| xcb::grab_key(&self.conn,
+                true, // owner_events, so our own window still receives them as usual while focused
+                self.root,
+                hotkey.modifiers | lock,
+                x_keycode,
+                xcb::GRAB_MODE_ASYNC as _,
+                xcb::GRAB_MODE_ASYNC as _,
+            ).request_check()).collect::<Result<Vec<_>, _>>();
+
+            match grabbed {
+                Ok(..) => self.hotkey_grabs.push((hotkey.id, keycode, hotkey.modifiers)),
+                Err(e) => warn!("failed to grab global hotkey {:?}, it may be in use by another application: {:?}", hotkey, e),
             }
+        }
 
-            if let Some(skip) = v.skip_threshold() {
-                if count >= skip {
-                    warn!("spin_send timed out");
-                    break
-                }
+        Ok(())
+    }
+
+    fn keysym_keycode(&self, keysym: xcb::Keysym) -> Option<xcb::Keycode> {
+        // only the unshifted and shifted levels of the first group
+        self.keys.keysyms().chunks(self.keys.keysyms_per_keycode() as usize)
+            .position(|keysyms| keysyms.iter().take(2).any(|&k| k == keysym))
+            .map(|code| code as _)
+    }
+
+    pub fn keymap(&self) -> XEvent {
+        XEvent::Keymap {
+            keysyms: self.keys.keysyms().to_owned(),
+            keysyms_per_keycode: self.keys.keysyms_per_keycode(),
+        }
+    }
+
+    fn process_hotkey(&mut self, pressed: bool, keycode: xcb::Keycode, state: u16) -> Option<XEvent> {
+        let id = if pressed {
+            let state = state & MOD_MASK_KEYBOARD & !self.lock_masks()[3];
+            let id = self.hotkey_grabs.iter()
+                .find(|&&(_, code, modifiers)| code == keycode && modifiers == state)
+                .map(|&(id, _, _)| id);
+            if let Some(id) = id {
+                self.hotkeys_pressed.push((keycode, id));
             }
+            id
+        } else {
+            // modifiers may already be released by now
+            let index = self.hotkeys_pressed.iter().position(|&(code, _)| code == keycode);
+            index.map(|i| self.hotkeys_pressed.remove(i).1)
+        };
+
+        id.map(|id| XEvent::Hotkey {
+            id: id,
+            pressed: pressed,
+        })
+    }
+
+    fn grab_devices(&mut self, devices: &[String]) -> Result<(), Error> {
+        if self.xinput_opcode.is_none() {
+            return Err(format_err!("XInput2 is required to grab devices"))
+        }
+
+        self.ungrab_devices()?;
+
+        let slaves: Vec<_> = xinput::query_devices(&self.conn)?.into_iter()
+            .filter(|info| match info.kind {
+                xinput::DEVICE_TYPE_SLAVE_KEYBOARD | xinput::DEVICE_TYPE_SLAVE_POINTER | xinput::DEVICE_TYPE_FLOATING_SLAVE => true,
+                _ => false,
+            }).collect();
 
-            if count < 0xffff {
-                count += 1;
+        let mask = xinput::EVENT_MASK_KEY_PRESS | xinput::EVENT_MASK_KEY_RELEASE |
+            xinput::EVENT_MASK_BUTTON_PRESS | xinput::EVENT_MASK_BUTTON_RELEASE |
+            xinput::EVENT_MASK_MOTION;
+
+        for name in devices {
+            // a single physical device often shows up as both a keyboard and a pointer
+            let ids: Vec<_> = slaves.iter().filter(|info| &info.name == name).map(|info| info.id).collect();
+            if ids.is_empty() {
+                warn!("X input device {:?} not found", name);
             }
 
-            sleep(Duration::from_millis(20));
+            for id in ids {
+                let status = xinput::grab_device(&self.conn, self.window, id, mask)?;
+                if let Err(e) = self.handle_grab_status(status) {
+                    self.ungrab_devices()?;
+                    return Err(e)
+                }
+                self.device_grabs.push(id);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ungrab_devices(&mut self) -> Result<(), Error> {
+        for id in self.device_grabs.drain(..) {
+            xinput::ungrab_device(&self.conn, id)?;
+        }
+
+        Ok(())
+    }
+
+    fn select_raw_motion(&mut self, enable: bool) -> Result<(), Error> {
+        if enable && self.xinput_opcode.is_none() {
+            warn!("XInput2 is required for raw motion, falling back to absolute motion");
+            return Ok(())
+        }
 
-            value = Some(v);
+        if enable || self.raw_motion {
+            let mask = if enable { xinput::EVENT_MASK_RAW_MOTION } else { 0 };
+            xinput::select_events(&self.conn, self.root, xinput::DEVICE_ALL_MASTER, mask)?;
         }
+        self.raw_motion = enable;
+        self.raw_remainder = (0.0, 0.0);
 
         Ok(())
     }
 
-    pub fn xmain(recv: Receiver<XRequest>, sender: &mut Sender<Result<XEvent, Error>>) -> Result<(), Error> {
-        #[derive(Clone)]
-        struct NotifyVoid;
-        impl Notify for NotifyVoid {
-            fn notify(&self, _id: usize) { }
+    fn process_raw_motion(&mut self, x: f64, y: f64, time: xcb::Timestamp) -> Option<XEvent> {
+        // carry sub-pixel motion over to the next event
+        let (rx, ry) = (self.raw_remainder.0 + x, self.raw_remainder.1 + y);
+        let (dx, dy) = (rx.trunc(), ry.trunc());
+        self.raw_remainder = (rx - dx, ry - dy);
+
+        if dx == 0.0 && dy == 0.0 {
+            None
+        } else {
+            Some(XEvent::MouseRelative {
+                x: dx as _,
+                y: dy as _,
+                time: time,
+            })
+        }
+    }
+
+    fn draw_status(&self) {
+        let (ascent, descent) = self.status_font;
+        xcb::clear_area(&self.conn, false, self.window, 0, 0, 0, 0);
+        for (i, line) in self.status.iter().enumerate() {
+            // text requests are limited to 255 bytes
+            let mut end = line.len().min(0xff);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            let y = STATUS_MARGIN + ascent + i as i16 * (ascent + descent);
+            xcb::image_text_8(&self.conn, self.window, self.status_gc, STATUS_MARGIN, y, &line[..end]);
+        }
+    }
+
+    fn selection_atom(&self, selection: XSelection) -> xcb::Atom {
+        match selection {
+            XSelection::Clipboard => self.atom_clipboard,
+            XSelection::Primary => xcb::ATOM_PRIMARY,
+        }
+    }
+
+    // asks the owner of the next pending selection to convert it
+    fn convert_selection(&mut self) {
+        if self.selection_pending.is_empty() {
+            return
+        }
+
+        let selection = self.selection_pending.remove(0);
+        let selection = self.selection_atom(selection);
+        xcb::convert_selection(&self.conn, self.window, selection, self.atom_utf8_string, self.atom_selection, xcb::CURRENT_TIME);
+    }
+
+    fn process_selection_notify(&mut self, property: xcb::Atom) -> Result<Option<XEvent>, xcb::GenericError> {
+        if property == xcb::NONE {
+            // the selection has no owner or couldn't be converted
+            self.convert_selection();
+            return Ok(None)
+        }
+
+        let reply = xcb::get_property(&self.conn, true, self.window, property, xcb::ATOM_ANY, 0, u32::max_value() / 4).get_reply()?;
+        self.selection_pending.clear();
+        if reply.type_() == self.atom_incr {
+            warn!("selection is too large to transfer");
+            return Ok(None)
+        }
+
+        let text = String::from_utf8_lossy(reply.value::<u8>()).into_owned();
+        Ok(if text.is_empty() {
+            None
+        } else {
+            Some(XEvent::Selection(text))
+        })
+    }
+
+    fn process_selection_request(&self, event: &xcb::SelectionRequestEvent) {
+        // obsolete clients leave out the property
+        let property = if event.property() == xcb::NONE { event.target() } else { event.property() };
+        let property = match self.selection_text {
+            Some(..) if event.target() == self.atom_targets => {
+                xcb::change_property(&self.conn,
+                    xcb::PROP_MODE_REPLACE as _,
+                    event.requestor(),
+                    property,
+                    xcb::ATOM_ATOM, 32,
+                    &[self.atom_targets, self.atom_utf8_string, xcb::ATOM_STRING]
+                );
+                property
+            },
+            Some(ref text) if event.target() == self.atom_utf8_string || event.target() == xcb::ATOM_STRING => {
+                xcb::change_property(&self.conn,
+                    xcb::PROP_MODE_REPLACE as _,
+                    event.requestor(),
+                    property,
+                    event.target(), 8,
+                    text.as_bytes()
+                );
+                property
+            },
+            _ => xcb::NONE,
+        };
+
+        let notify = xcb::SelectionNotifyEvent::new(event.time(), event.requestor(), event.selection(), event.target(), property);
+        xcb::send_event(&self.conn, false, event.requestor(), 0, &notify);
+    }
+
+    fn pointer_center(&self) -> (i16, i16) {
+        ((self.state.width / 2) as _, (self.state.height / 2) as _)
+    }
+
+    fn warp_center(&self) {
+        let (x, y) = self.pointer_center();
+        xcb::warp_pointer(&self.conn, xcb::NONE, self.window, 0, 0, 0, 0, x, y);
+    }
+
+    fn process_pointer_lock(&mut self, x: i16, y: i16, time: xcb::Timestamp) -> Option<XEvent> {
+        let (cx, cy) = self.pointer_center();
+        if (x, y) == (cx, cy) {
+            // our own warp
+            return None
+        }
+
+        self.warp_center();
+
+        if self.raw_motion {
+            None
+        } else {
+            Some(XEvent::MouseRelative {
+                x: (x - cx) as _,
+                y: (y - cy) as _,
+                time: time,
+            })
+        }
+    }
+
+    fn process_xinput_event(&mut self, event: &xcb::GenericEvent) -> Option<XEvent> {
+        if let Some((x, y, time)) = self.xinput_opcode.and_then(|opcode| xinput::raw_motion(opcode, event)) {
+            // buttons still arrive through the core grab
+            return if self.raw_motion {
+                self.process_raw_motion(x, y, time)
+            } else {
+                None
+            }
+        }
+
+        let event = match self.xinput_opcode.and_then(|opcode| xinput::device_event(opcode, event)) {
+            Some(event) => event,
+            None => {
+                info!("unknown X generic event");
+                return None
+            },
+        };
+
+        match event.event_type {
+            xinput::KEY_PRESS | xinput::KEY_RELEASE => {
+                if event.flags & xinput::KEY_EVENT_FLAGS_KEY_REPEAT != 0 {
+                    return None
+                }
+
+                let keycode = self.keycode(event.detail as _);
+                Some(XEvent::Key {
+                    pressed: event.event_type == xinput::KEY_PRESS,
+                    keycode: keycode,
+                    keysym: self.keysym(keycode),
+                    state: event.mods as _,
+                    time: event.time,
+                })
+            },
+            xinput::BUTTON_PRESS | xinput::BUTTON_RELEASE => Some(XEvent::Button {
+                pressed: event.event_type == xinput::BUTTON_PRESS,
+                button: event.detail as _,
+                state: event.mods as _,
+                time: event.time,
+            }),
+            _ => Some(XEvent::Mouse {
+                // coordinates are 16.16 fixed point
+                x: (event.event_x >> 16) as _,
+                y: (event.event_y >> 16) as _,
+                time: event.time,
+            }),
+        }
+    }
+
+    pub fn keysym(&self, code: xcb::Keycode) -> Option<xcb::Keysym> {
+        let modifier = 0; // TODO: ?
+        match self.keys.keysyms().get(code as usize * self.keys.keysyms_per_keycode() as usize + modifier).cloned() {
+            Some(0) => None,
+            keysym => keysym,
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.state.running = false;
+    }
+
+    // merges motion into the last queued event when both are of the same kind
+    fn queue_event(pending: &mut VecDeque<Result<XEvent, Error>>, event: XEvent) {
+        match (pending.back_mut(), event) {
+            (Some(&mut Ok(XEvent::Mouse { ref mut x, ref mut y, ref mut time })), XEvent::Mouse { x: new_x, y: new_y, time: new_time }) => {
+                *x = new_x;
+                *y = new_y;
+                *time = new_time;
+            },
+            (Some(&mut Ok(XEvent::MouseRelative { ref mut x, ref mut y, ref mut time })), XEvent::MouseRelative { x: dx, y: dy, time: new_time }) => {
+                *x = x.saturating_add(dx);
+                *y = y.saturating_add(dy);
+                *time = new_time;
+            },
+            (_, event) => pending.push_back(Ok(event)),
+        }
+    }
+
+    // returns false once the receiver is gone
+    fn send_pending(sender: &mut executor::Spawn<&mut Sender<Result<XEvent, Error>>>, notify: &NotifyHandle, pending: &mut VecDeque<Result<XEvent, Error>>) -> bool {
+        while let Some(event) = pending.pop_front() {
+            match sender.start_send_notify(event, notify, 0) {
+                Ok(AsyncSink::Ready) => (),
+                Ok(AsyncSink::NotReady(event)) => {
+                    // the channel is full, wait to be notified once there's room again
+                    pending.push_front(event);
+                    break
+                },
+                Err(..) => return false,
+            }
         }
-        let notify = NotifyHandle::from(Arc::new(NotifyVoid));
 
-        let mut xcontext = Self::connect()?;
+        true
+    }
+
+    pub fn xmain(window_config: XWindowConfig, recv: Receiver<XRequest>, sender: &mut Sender<Result<XEvent, Error>>) -> Result<(), Error> {
+        struct NotifyThread(thread::Thread);
+        impl Notify for NotifyThread {
+            fn notify(&self, _id: usize) {
+                self.0.unpark()
+            }
+        }
+        let notify = NotifyHandle::from(Arc::new(NotifyThread(thread::current())));
+
+        let mut xcontext = Self::connect(window_config)?;
         xcontext.state.running = true;
         xcontext.map_window()?;
 
+        let mut pending = VecDeque::new();
+        pending.push_back(Ok(xcontext.keymap()));
+        pending.push_back(xcontext.lock_state());
+
         let mut recv = executor::spawn(recv);
+        let mut sender = executor::spawn(sender);
 
         while xcontext.state.running {
+            // merge motion that has already been read from the connection before handing it over
+            while let Some(event) = xcontext.poll_queued_event() {
+                if let Some(processed) = xcontext.process_event(&event)? {
+                    Self::queue_event(&mut pending, processed);
+                }
+            }
+
+            if !Self::send_pending(&mut sender, &notify, &mut pending) {
+                break
+            }
+
             // poll for request
             let processed = match recv.poll_stream_notify(&notify, 0) {
                 Ok(Async::Ready(None)) => break, // treat this as a request to exit?
@@ -307,19 +1023,22 @@ impl XContext {
             // otherwise block on x event
             let processed = match processed {
                 Some(processed) => Some(processed),
-                None => {
+                None if pending.is_empty() => {
                     let event = &xcontext.pump()?;
                     xcontext.process_event(&event)?
                 },
+                // keep reading X events while waiting on the reactor, so motion can be merged
+                None => match xcontext.poll_event()? {
+                    Some(ref event) => xcontext.process_event(event)?,
+                    None => {
+                        thread::park_timeout(Duration::from_millis(PENDING_POLL_MS));
+                        None
+                    },
+                },
             };
 
-            // send processed event
             if let Some(processed) = processed {
-                match Self::spin_send(sender, Ok(processed)) {
-                    Ok(..) => (),
-                    Err(ref err) if err.is_disconnected() => break,
-                    Err(err) => return Err(err.into()),
-                }
+                Self::queue_event(&mut pending, processed);
             }
         }
 
@@ -367,7 +1086,7 @@ impl XContext {
             XRequest::UnstickGuest => {
                 Some(XEvent::UnstickGuest)
             },
-            XRequest::Grab => {
+            XRequest::Grab { raw_motion, pointer_lock } => {
                 let status = xcb::grab_keyboard(&self.conn,
                     false, // owner_events, I don't quite understand how this works
                     self.window,
@@ -383,19 +1102,67 @@ impl XContext {
                     xcb::GRAB_MODE_ASYNC as _,
                     xcb::GRAB_MODE_ASYNC as _,
                     self.window, // confine mouse to our window
-                    xcb::NONE,
+                    if pointer_lock { self.blank_cursor } else { xcb::NONE },
                     xcb::CURRENT_TIME,
                 ).get_reply()?.status();
                 self.handle_grab_status(status)?;
+                self.select_raw_motion(raw_motion)?;
+                self.pointer_lock = pointer_lock;
+                if pointer_lock {
+                    self.warp_center();
+                }
                 self.state.grabbed = true;
                 Some(XEvent::State(self.state.clone()))
             },
             XRequest::Ungrab => {
                 xcb::ungrab_keyboard(&self.conn, xcb::CURRENT_TIME).request_check()?;
                 xcb::ungrab_pointer(&self.conn, xcb::CURRENT_TIME).request_check()?;
+                self.select_raw_motion(false)?;
+                self.pointer_lock = false;
                 self.state.grabbed = false;
                 Some(XEvent::State(self.state.clone()))
             },
+            XRequest::GrabHotkeys(ref hotkeys) => {
+                self.grab_hotkeys(hotkeys)?;
+                None
+            },
+            XRequest::GrabDevices(ref devices) => {
+                // devices can be missing or already grabbed by another client
+                if let Err(e) = self.grab_devices(devices) {
+                    warn!("Failed to grab X input devices {} {:?}", e, e);
+                }
+                self.state.devices_grabbed = !self.device_grabs.is_empty();
+                Some(XEvent::State(self.state.clone()))
+            },
+            XRequest::UngrabDevices => {
+                self.ungrab_devices()?;
+                self.state.devices_grabbed = false;
+                Some(XEvent::State(self.state.clone()))
+            },
+            XRequest::GetSelection(ref selections) => {
+                self.selection_pending = selections.clone();
+                self.convert_selection();
+                self.flush()?;
+                None
+            },
+            XRequest::SetSelection(ref selections, ref text) => {
+                self.selection_text = Some(text.clone());
+                for &selection in selections {
+                    let selection = self.selection_atom(selection);
+                    xcb::set_selection_owner(&self.conn, self.window, selection, xcb::CURRENT_TIME).request_check()?;
+                }
+                None
+            },
+            XRequest::SetStatus(ref status) => {
+                self.status = status.clone();
+                self.draw_status();
+                self.flush()?;
+                None
+            },
+            XRequest::SetLockState(locks) => {
+                self.set_lock_state(locks)?;
+                None
+            },
         })
     }
 
@@ -502,6 +1269,11 @@ impl XContext {
                 }
 
                 let keycode = self.keycode(event.detail());
+                if event.event() == self.root {
+                    // only global hotkey grabs are reported relative to the root window
+                    return Ok(self.process_hotkey(kind == xcb::KEY_PRESS, keycode, event.state()))
+                }
+
                 let keysym = self.keysym(keycode);
 
                 Some(XEvent::Key {
@@ -509,6 +1281,7 @@ impl XContext {
                     keycode: keycode,
                     keysym: if keysym == Some(0) { None } else { keysym },
                     state: event.state(),
+                    time: event.time(),
                 })
             },
             xcb::BUTTON_PRESS | xcb::BUTTON_RELEASE => {
@@ -517,13 +1290,20 @@ impl XContext {
                     pressed: kind == xcb::BUTTON_PRESS,
                     button: event.detail(),
                     state: event.state(),
+                    time: event.time(),
                 })
             },
+            xcb::MOTION_NOTIFY if self.pointer_lock => {
+                let event = unsafe { xcb::cast_event::<xcb::MotionNotifyEvent>(event) };
+                self.process_pointer_lock(event.event_x(), event.event_y(), event.time())
+            },
+            xcb::MOTION_NOTIFY if self.raw_motion => None,
             xcb::MOTION_NOTIFY => {
                 let event = unsafe { xcb::cast_event::<xcb::MotionNotifyEvent>(event) };
                 Some(XEvent::Mouse {
                     x: event.event_x(),
                     y: event.event_y(),
+                    time: event.time(),
                 })
             },
             xcb::MAPPING_NOTIFY => {
@@ -531,6 +1311,32 @@ impl XContext {
                 self.keys = xcb::get_keyboard_mapping(&self.conn, setup.min_keycode(), setup.max_keycode() - setup.min_keycode()).get_reply()?;
                 self.mods = xcb::get_modifier_mapping(&self.conn).get_reply()?;
 
+                let hotkeys = self.hotkeys.clone();
+                if let Err(e) = self.grab_hotkeys(&hotkeys) {
+                    warn!("failed to regrab global hotkeys after a keymap change: {}", e);
+                }
+
+                Some(self.keymap())
+            },
+            xcb::SELECTION_NOTIFY => {
+                let event = unsafe { xcb::cast_event::<xcb::SelectionNotifyEvent>(event) };
+                self.process_selection_notify(event.property())?
+            },
+            xcb::SELECTION_REQUEST => {
+                let event = unsafe { xcb::cast_event::<xcb::SelectionRequestEvent>(event) };
+                self.process_selection_request(event);
+                None
+            },
+            xcb::SELECTION_CLEAR => {
+                // another client took over the selection
+                None
+            },
+            xcb::EXPOSE => {
+                let event = unsafe { xcb::cast_event::<xcb::ExposeEvent>(event) };
+                // redraw once the last of a series of exposures arrives
+                if event.count() == 0 {
+                    self.draw_status();
+                }
                 None
             },
             xcb::CONFIGURE_NOTIFY => {
@@ -539,6 +1345,16 @@ impl XContext {
                 self.state.height = event.height();
                 Some(XEvent::State(self.state.clone()))
             },
+            xcb::GE_GENERIC => self.process_xinput_event(event),
+            kind if Some(kind) == self.xkb_event => self.process_xkb_event(event),
+            kind if self.randr_event.map(|e| kind == e + xcb::randr::SCREEN_CHANGE_NOTIFY || kind == e + xcb::randr::NOTIFY).unwrap_or(false) => {
+                if !self.window_config.monitor.is_empty() {
+                    if let Err(e) = self.process_randr_event() {
+                        warn!("failed to follow the monitor to its new output: {}", e);
+                    }
+                }
+                None
+            },
             _ => {
                 info!("unknown X event {}", event.response_type());
                 None
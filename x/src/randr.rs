@@ -0,0 +1,127 @@
+// Finds the RandR output a monitor is connected to by matching its EDID.
+
+use xcb::{self, randr, GenericError};
+
+#[derive(Debug, Clone, Default)]
+pub struct XMonitor {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl XMonitor {
+    pub fn is_empty(&self) -> bool {
+        self.manufacturer.is_none() && self.model.is_none() && self.serial.is_none()
+    }
+
+    fn matches(&self, edid: &Edid) -> bool {
+        [
+            (Some(&edid.manufacturer), &self.manufacturer),
+            (edid.model.as_ref(), &self.model),
+            (edid.serial.as_ref(), &self.serial),
+        ].iter().filter_map(|&(e, m)| m.as_ref().map(|m| (e, m)))
+            .all(|(e, m)| e == Some(m))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputGeometry {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Debug)]
+struct Edid {
+    manufacturer: String,
+    model: Option<String>,
+    serial: Option<String>,
+}
+
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const DESCRIPTOR_SERIAL: u8 = 0xff;
+const DESCRIPTOR_NAME: u8 = 0xfc;
+
+fn parse_edid(edid: &[u8]) -> Option<Edid> {
+    if edid.len() < 128 || edid[..8] != EDID_HEADER {
+        return None
+    }
+
+    // three letters packed into five bits each, 1 being 'A'
+    let id = (edid[8] as u16) << 8 | edid[9] as u16;
+    let manufacturer = [10, 5, 0].iter()
+        .map(|&shift| (b'A' - 1 + (id >> shift & 0x1f) as u8) as char)
+        .collect();
+
+    let mut model = None;
+    let mut serial = None;
+    for descriptor in edid[54..126].chunks(18) {
+        // detailed timings don't start with a zero pixel clock
+        if descriptor[..3] != [0, 0, 0] {
+            continue
+        }
+
+        let text = || String::from_utf8_lossy(&descriptor[5..])
+            .split('\n').next().unwrap_or_default()
+            .trim().to_owned();
+        match descriptor[3] {
+            DESCRIPTOR_NAME => model = Some(text()),
+            DESCRIPTOR_SERIAL => serial = Some(text()),
+            _ => (),
+        }
+    }
+
+    Some(Edid {
+        manufacturer: manufacturer,
+        model: model,
+        serial: serial,
+    })
+}
+
+// the first event code of RandR 1.2, if available
+pub fn first_event(conn: &xcb::Connection) -> Option<u8> {
+    let first_event = match xcb::query_extension(conn, "RANDR").get_reply() {
+        Ok(ref ext) if ext.present() => ext.first_event(),
+        _ => return None,
+    };
+
+    match randr::query_version(conn, 1, 2).get_reply() {
+        Ok(ref version) if version.major_version() > 1 || version.minor_version() >= 2 => Some(first_event),
+        _ => None,
+    }
+}
+
+pub fn select_events(conn: &xcb::Connection, root: xcb::Window) -> Result<(), GenericError> {
+    randr::select_input(conn, root,
+        (randr::NOTIFY_MASK_SCREEN_CHANGE | randr::NOTIFY_MASK_CRTC_CHANGE | randr::NOTIFY_MASK_OUTPUT_CHANGE) as _
+    ).request_check()
+}
+
+pub fn find_output(conn: &xcb::Connection, root: xcb::Window, atom_edid: xcb::Atom, monitor: &XMonitor) -> Result<Option<OutputGeometry>, GenericError> {
+    let resources = randr::get_screen_resources_current(conn, root).get_reply()?;
+    let timestamp = resources.config_timestamp();
+
+    for &output in resources.outputs() {
+        let info = randr::get_output_info(conn, output, timestamp).get_reply()?;
+        if info.connection() != randr::CONNECTION_CONNECTED as u8 || info.crtc() == xcb::NONE {
+            continue
+        }
+
+        let edid = randr::get_output_property(conn, output, atom_edid, xcb::ATOM_ANY, 0, 128, false, false).get_reply()?;
+        match parse_edid(edid.data()) {
+            Some(ref edid) if monitor.matches(edid) => (),
+            _ => continue,
+        }
+
+        let crtc = randr::get_crtc_info(conn, info.crtc(), timestamp).get_reply()?;
+        return Ok(Some(OutputGeometry {
+            x: crtc.x(),
+            y: crtc.y(),
+            width: crtc.width(),
+            height: crtc.height(),
+        }))
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,347 @@
+// Just enough of XInput2 for device grabs, since the xinput feature of xcb 0.8
+// doesn't build.
+
+use std::{ptr, slice};
+use libc::{self, c_char, c_int, c_uint};
+use xcb::{self, GenericError, GenericEvent};
+use xcb::ffi::base::{xcb_connection_t, xcb_generic_error_t, xcb_void_cookie_t};
+
+pub type DeviceId = u16;
+
+pub const DEVICE_ALL: DeviceId = 0;
+pub const DEVICE_ALL_MASTER: DeviceId = 1;
+
+pub const DEVICE_TYPE_SLAVE_POINTER: u16 = 3;
+pub const DEVICE_TYPE_SLAVE_KEYBOARD: u16 = 4;
+pub const DEVICE_TYPE_FLOATING_SLAVE: u16 = 5;
+
+pub const GRAB_MODE_ASYNC: u8 = 1;
+
+pub const KEY_PRESS: u16 = 2;
+pub const KEY_RELEASE: u16 = 3;
+pub const BUTTON_PRESS: u16 = 4;
+pub const BUTTON_RELEASE: u16 = 5;
+pub const MOTION: u16 = 6;
+pub const RAW_MOTION: u16 = 17;
+
+pub const EVENT_MASK_KEY_PRESS: u32 = 1 << KEY_PRESS;
+pub const EVENT_MASK_KEY_RELEASE: u32 = 1 << KEY_RELEASE;
+pub const EVENT_MASK_BUTTON_PRESS: u32 = 1 << BUTTON_PRESS;
+pub const EVENT_MASK_BUTTON_RELEASE: u32 = 1 << BUTTON_RELEASE;
+pub const EVENT_MASK_MOTION: u32 = 1 << MOTION;
+pub const EVENT_MASK_RAW_MOTION: u32 = 1 << RAW_MOTION;
+
+pub const KEY_EVENT_FLAGS_KEY_REPEAT: u32 = 0x10000;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Cookie {
+    sequence: c_uint,
+}
+
+#[repr(C)]
+struct QueryVersionReply {
+    response_type: u8,
+    pad0: u8,
+    sequence: u16,
+    length: u32,
+    major_version: u16,
+    minor_version: u16,
+    pad1: [u8; 20],
+}
+
+#[repr(C)]
+struct QueryDeviceReply {
+    response_type: u8,
+    pad0: u8,
+    sequence: u16,
+    length: u32,
+    num_infos: u16,
+    pad1: [u8; 22],
+}
+
+#[repr(C)]
+struct DeviceInfoRaw {
+    deviceid: DeviceId,
+    type_: u16,
+    attachment: DeviceId,
+    num_classes: u16,
+    name_len: u16,
+    enabled: u8,
+    pad0: u8,
+}
+
+#[repr(C)]
+struct DeviceInfoIterator {
+    data: *mut DeviceInfoRaw,
+    rem: c_int,
+    index: c_int,
+}
+
+#[repr(C)]
+struct GrabDeviceReply {
+    response_type: u8,
+    pad0: u8,
+    sequence: u16,
+    length: u32,
+    status: u8,
+    pad1: [u8; 23],
+}
+
+#[repr(C)]
+struct DeviceEventRaw {
+    response_type: u8,
+    extension: u8,
+    sequence: u16,
+    length: u32,
+    event_type: u16,
+    deviceid: DeviceId,
+    time: u32,
+    detail: u32,
+    root: u32,
+    event: u32,
+    child: u32,
+    full_sequence: u32,
+    root_x: i32,
+    root_y: i32,
+    event_x: i32,
+    event_y: i32,
+    buttons_len: u16,
+    valuators_len: u16,
+    sourceid: DeviceId,
+    pad0: [u8; 2],
+    flags: u32,
+    mods_base: u32,
+    mods_latched: u32,
+    mods_locked: u32,
+    mods_effective: u32,
+}
+
+#[repr(C)]
+struct EventMask {
+    deviceid: DeviceId,
+    mask_len: u16,
+    mask: u32,
+}
+
+#[repr(C)]
+struct RawEventRaw {
+    response_type: u8,
+    extension: u8,
+    sequence: u16,
+    length: u32,
+    event_type: u16,
+    deviceid: DeviceId,
+    time: u32,
+    detail: u32,
+    sourceid: DeviceId,
+    valuators_len: u16,
+    flags: u32,
+    pad0: [u8; 4],
+    full_sequence: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Fp3232 {
+    integral: i32,
+    frac: u32,
+}
+
+#[link(name = "xcb-xinput")]
+extern "C" {
+    fn xcb_input_xi_query_version(c: *mut xcb_connection_t, major_version: u16, minor_version: u16) -> Cookie;
+    fn xcb_input_xi_query_version_reply(c: *mut xcb_connection_t, cookie: Cookie, error: *mut *mut xcb_generic_error_t) -> *mut QueryVersionReply;
+
+    fn xcb_input_xi_query_device(c: *mut xcb_connection_t, deviceid: DeviceId) -> Cookie;
+    fn xcb_input_xi_query_device_reply(c: *mut xcb_connection_t, cookie: Cookie, error: *mut *mut xcb_generic_error_t) -> *mut QueryDeviceReply;
+    fn xcb_input_xi_query_device_infos_iterator(r: *const QueryDeviceReply) -> DeviceInfoIterator;
+    fn xcb_input_xi_device_info_next(i: *mut DeviceInfoIterator);
+    fn xcb_input_xi_device_info_name(r: *const DeviceInfoRaw) -> *mut c_char;
+    fn xcb_input_xi_device_info_name_length(r: *const DeviceInfoRaw) -> c_int;
+
+    fn xcb_input_xi_grab_device(c: *mut xcb_connection_t, window: u32, time: u32, cursor: u32, deviceid: DeviceId, mode: u8, paired_device_mode: u8, owner_events: u8, mask_len: u16, mask: *const u32) -> Cookie;
+    fn xcb_input_xi_grab_device_reply(c: *mut xcb_connection_t, cookie: Cookie, error: *mut *mut xcb_generic_error_t) -> *mut GrabDeviceReply;
+
+    fn xcb_input_xi_select_events_checked(c: *mut xcb_connection_t, window: u32, num_mask: u16, masks: *const EventMask) -> xcb_void_cookie_t;
+
+    fn xcb_input_xi_ungrab_device_checked(c: *mut xcb_connection_t, time: u32, deviceid: DeviceId) -> xcb_void_cookie_t;
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub kind: u16,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceEvent {
+    pub event_type: u16,
+    pub time: xcb::Timestamp,
+    pub detail: u32,
+    // 16.16 fixed point
+    pub event_x: i32,
+    pub event_y: i32,
+    pub flags: u32,
+    pub mods: u32,
+}
+
+unsafe fn reply<T, R, F: FnOnce(&T) -> R>(reply: *mut T, error: *mut xcb_generic_error_t, f: F) -> Result<R, GenericError> {
+    if reply.is_null() {
+        Err(GenericError { ptr: error })
+    } else {
+        let res = f(&*reply);
+        libc::free(reply as *mut _);
+        Ok(res)
+    }
+}
+
+// the major opcode of the extension if XInput 2.0 or later is supported
+pub fn opcode(conn: &xcb::Connection) -> Option<u8> {
+    let opcode = match xcb::query_extension(conn, "XInputExtension").get_reply() {
+        Ok(ref ext) if ext.present() => ext.major_opcode(),
+        _ => return None,
+    };
+
+    let version = unsafe {
+        let c = conn.get_raw_conn();
+        let mut error = ptr::null_mut();
+        let cookie = xcb_input_xi_query_version(c, 2, 0);
+        reply(xcb_input_xi_query_version_reply(c, cookie, &mut error), error, |r| r.major_version)
+    };
+
+    match version {
+        Ok(major) if major >= 2 => Some(opcode),
+        _ => None,
+    }
+}
+
+pub fn query_devices(conn: &xcb::Connection) -> Result<Vec<DeviceInfo>, GenericError> {
+    unsafe {
+        let c = conn.get_raw_conn();
+        let mut error = ptr::null_mut();
+        let cookie = xcb_input_xi_query_device(c, DEVICE_ALL);
+        reply(xcb_input_xi_query_device_reply(c, cookie, &mut error), error, |r| {
+            let mut devices = Vec::new();
+            let mut iter = xcb_input_xi_query_device_infos_iterator(r);
+            while iter.rem > 0 {
+                let info = &*iter.data;
+                let name = slice::from_raw_parts(
+                    xcb_input_xi_device_info_name(info) as *const u8,
+                    xcb_input_xi_device_info_name_length(info) as usize
+                );
+                devices.push(DeviceInfo {
+                    id: info.deviceid,
+                    kind: info.type_,
+                    name: String::from_utf8_lossy(name).into_owned(),
+                });
+                xcb_input_xi_device_info_next(&mut iter);
+            }
+            devices
+        })
+    }
+}
+
+// returns the grab status
+pub fn grab_device(conn: &xcb::Connection, window: xcb::Window, device: DeviceId, mask: u32) -> Result<u8, GenericError> {
+    unsafe {
+        let c = conn.get_raw_conn();
+        let mut error = ptr::null_mut();
+        let mask = [mask];
+        let cookie = xcb_input_xi_grab_device(c,
+            window,
+            xcb::CURRENT_TIME,
+            xcb::NONE,
+            device,
+            GRAB_MODE_ASYNC,
+            GRAB_MODE_ASYNC,
+            0, // owner_events
+            mask.len() as _, mask.as_ptr(),
+        );
+        reply(xcb_input_xi_grab_device_reply(c, cookie, &mut error), error, |r| r.status)
+    }
+}
+
+pub fn ungrab_device(conn: &xcb::Connection, device: DeviceId) -> Result<(), GenericError> {
+    unsafe {
+        let c = conn.get_raw_conn();
+        let cookie = xcb_input_xi_ungrab_device_checked(c, xcb::CURRENT_TIME, device);
+        request_check(c, cookie)
+    }
+}
+
+// raw events are only ever delivered to the root window
+pub fn select_events(conn: &xcb::Connection, window: xcb::Window, device: DeviceId, mask: u32) -> Result<(), GenericError> {
+    unsafe {
+        let c = conn.get_raw_conn();
+        let mask = EventMask {
+            deviceid: device,
+            mask_len: 1,
+            mask: mask,
+        };
+        let cookie = xcb_input_xi_select_events_checked(c, window, 1, &mask);
+        request_check(c, cookie)
+    }
+}
+
+unsafe fn request_check(c: *mut xcb_connection_t, cookie: xcb_void_cookie_t) -> Result<(), GenericError> {
+    let error = xcb::ffi::base::xcb_request_check(c, cookie);
+    if error.is_null() {
+        Ok(())
+    } else {
+        Err(GenericError { ptr: error })
+    }
+}
+
+// the unaccelerated relative motion of the first two valuators, usually x and y
+pub fn raw_motion(opcode: u8, event: &GenericEvent) -> Option<(f64, f64, xcb::Timestamp)> {
+    unsafe {
+        let raw = &*(event.ptr as *const RawEventRaw);
+        if raw.response_type & !0x80 != xcb::GE_GENERIC || raw.extension != opcode || raw.event_type != RAW_MOTION {
+            return None
+        }
+
+        // the valuator mask is followed by the accelerated values and then the raw values,
+        // each a 32.32 fixed point number for every bit set in the mask
+        let mask = slice::from_raw_parts((event.ptr as *const RawEventRaw).offset(1) as *const u32, raw.valuators_len as usize);
+        let count = mask.iter().map(|m| m.count_ones() as usize).sum::<usize>();
+        let values = slice::from_raw_parts(mask.as_ptr().offset(mask.len() as isize) as *const Fp3232, count * 2);
+        let values = &values[count..];
+
+        let mut axes = [0f64; 2];
+        let mut index = 0;
+        for axis in 0..mask.len() * 32 {
+            if mask[axis / 32] & (1 << (axis % 32)) != 0 {
+                if axis < axes.len() {
+                    let value = values[index];
+                    axes[axis] = value.integral as f64 + value.frac as f64 / (1u64 << 32) as f64;
+                }
+                index += 1;
+            }
+        }
+
+        Some((axes[0], axes[1], raw.time))
+    }
+}
+
+// parses key, button and motion events belonging to the extension
+pub fn device_event(opcode: u8, event: &GenericEvent) -> Option<DeviceEvent> {
+    let event = unsafe { &*(event.ptr as *const DeviceEventRaw) };
+    if event.response_type & !0x80 != xcb::GE_GENERIC || event.extension != opcode {
+        return None
+    }
+
+    match event.event_type {
+        KEY_PRESS | KEY_RELEASE | BUTTON_PRESS | BUTTON_RELEASE | MOTION => Some(DeviceEvent {
+            event_type: event.event_type,
+            time: event.time,
+            detail: event.detail,
+            event_x: event.event_x,
+            event_y: event.event_y,
+            flags: event.flags,
+            mods: event.mods_effective,
+        }),
+        _ => None,
+    }
+}